@@ -0,0 +1,128 @@
+use std::time::{Duration, Instant};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use druid::im::Vector;
+use druid::piet::{FontBuilder, PietText, Text};
+use druid::text::EditAction;
+use nolex::*;
+use nolex::editor::*;
+
+// documents are a json array of numbers `[1, 1, ...]`, the sizes are in tokens
+const SIZES: [usize; 2] = [100_000, 1_000_000];
+
+fn document(size: usize) -> Tokens {
+    let mut tokens = Vector::new();
+    tokens.push_back(Token::new(5, "["));
+    while tokens.len() + 2 < size {
+        tokens.push_back(Token::new(8, "1"));
+        tokens.push_back(Token::new(2, ","));
+    }
+    tokens.push_back(Token::new(8, "1"));
+    tokens.push_back(Token::new(6, "]"));
+    tokens
+}
+
+fn state(size: usize) -> EditorState {
+    EditorState::with_tokens(&nolex::languages::json::INSTANCE, document(size))
+}
+
+/// a digit typed into a number in the middle, which is lexed again, only the typing is measured and the number is
+/// set back by a backspace
+fn insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert");
+    group.sample_size(10);
+    for &size in &SIZES {
+        let mut state = state(size);
+        let middle = size / 2 | 1;
+        group.bench_function(BenchmarkId::from_parameter(size), |b| b.iter_custom(|iters| {
+            let mut time = Duration::new(0, 0);
+            for _ in 0..iters {
+                state.set_cursor(middle, 1);
+                let start = Instant::now();
+                state.do_edit_action(EditAction::Insert(String::from("2")));
+                time += start.elapsed();
+                state.do_edit_action(EditAction::Backspace);
+            }
+            time
+        }));
+    }
+    group.finish();
+}
+
+/// a digit typed into a number in the middle is deleted again, only the backspace is measured
+fn delete(c: &mut Criterion) {
+    let mut group = c.benchmark_group("delete");
+    group.sample_size(10);
+    for &size in &SIZES {
+        let mut state = state(size);
+        let middle = size / 2 | 1;
+        group.bench_function(BenchmarkId::from_parameter(size), |b| b.iter_custom(|iters| {
+            let mut time = Duration::new(0, 0);
+            for _ in 0..iters {
+                state.set_cursor(middle, 1);
+                state.do_edit_action(EditAction::Insert(String::from("2")));
+                let start = Instant::now();
+                state.do_edit_action(EditAction::Backspace);
+                time += start.elapsed();
+            }
+            time
+        }));
+    }
+    group.finish();
+}
+
+/// a number in the middle is completed to `true`, which changes its type, so the tree is parsed again
+/// incrementally. only that is measured, and the number is set back by completing it again
+fn reparse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reparse");
+    group.sample_size(10);
+    for &size in &SIZES {
+        let mut state = state(size);
+        let middle = size / 2 | 1;
+        group.bench_function(BenchmarkId::from_parameter(size), |b| b.iter_custom(|iters| {
+            let mut time = Duration::new(0, 0);
+            for _ in 0..iters {
+                let start = Instant::now();
+                state.complete(middle, 9);
+                time += start.elapsed();
+                state.complete(middle, 8);
+            }
+            time
+        }));
+    }
+    group.finish();
+}
+
+fn reparse_all(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reparse_all");
+    group.sample_size(10);
+    for &size in &SIZES {
+        let mut state = state(size);
+        group.bench_function(BenchmarkId::from_parameter(size), |b| b.iter(|| state.reparse_all()));
+    }
+    group.finish();
+}
+
+/// an undo checkpoint clones the tokens
+fn clone(c: &mut Criterion) {
+    let mut group = c.benchmark_group("clone");
+    for &size in &SIZES {
+        let tokens = document(size);
+        group.bench_function(BenchmarkId::from_parameter(size), |b| b.iter(|| tokens.clone()));
+    }
+    group.finish();
+}
+
+/// uses the cairo text of linux
+fn layout(c: &mut Criterion) {
+    let mut group = c.benchmark_group("layout");
+    group.sample_size(10);
+    let font = PietText::new().new_font_by_name("JetBrains Mono", 14.0).build().unwrap();
+    for &size in &SIZES {
+        let state = state(size);
+        group.bench_function(BenchmarkId::from_parameter(size), |b| b.iter(|| layout_document(&state, PietText::new(), &font, 800.0)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, insert, delete, reparse, reparse_all, clone, layout);
+criterion_main!(benches);
@@ -1,17 +1,627 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
 use druid::im::Vector;
+use druid::text::{EditableText, Movement, Selection, movement, offset_for_delete_backwards};
+use unicode_segmentation::UnicodeSegmentation;
+use xi_rope::Rope;
+
+/// a text longer than this is kept in a rope, and back inline when it is half of it
+const LARGE: usize = 64 * 1024;
+/// bytes around the caret read from a rope for a movement
+const WINDOW: usize = 1024;
+
+/// the text of a token, a large one is a rope, so clone is cheap and edits are O(log n)
+#[derive(Clone, Debug)]
+pub enum TokenText {
+    Inline(String),
+    Rope(Rope)
+}
+
+impl TokenText {
+    pub fn new(str: String) -> TokenText {
+        if str.len() > LARGE {
+            TokenText::Rope(Rope::from(str))
+        } else {
+            TokenText::Inline(str)
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            TokenText::Inline(s) => s.len(),
+            TokenText::Rope(r) => r.len()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// the whole text, a rope is copied
+    pub fn as_str(&self) -> Cow<str> {
+        match self {
+            TokenText::Inline(s) => Cow::Borrowed(s),
+            TokenText::Rope(r) => r.slice_to_cow(..)
+        }
+    }
+
+    /// `range` is on char boundaries
+    pub fn slice(&self, range: Range<usize>) -> Cow<str> {
+        match self {
+            TokenText::Inline(s) => Cow::Borrowed(&s[range]),
+            TokenText::Rope(r) => r.slice_to_cow(range)
+        }
+    }
+
+    /// a rope is never borrowed as a whole
+    pub fn inline(&self) -> Option<&str> {
+        match self {
+            TokenText::Inline(s) => Some(s),
+            TokenText::Rope(_) => None
+        }
+    }
+
+    pub fn edit(&mut self, range: Range<usize>, new: &str) {
+        let changed = match self {
+            TokenText::Inline(s) => {
+                s.replace_range(range, new);
+                if s.len() > LARGE { Some(TokenText::Rope(Rope::from(s.as_str()))) } else { None }
+            },
+            TokenText::Rope(r) => {
+                r.edit(range, new);
+                if r.len() < LARGE / 2 { Some(TokenText::Inline(r.slice_to_cow(..).into_owned())) } else { None }
+            }
+        };
+        if let Some(text) = changed {
+            *self = text;
+        }
+    }
+
+    /// the text split at `\n` like `str::split`, and where each line starts. a rope is read line by line, not copied
+    /// as a whole
+    pub fn lines(&self) -> Box<dyn Iterator<Item=(usize, Cow<str>)> + '_> {
+        let mut start = 0;
+        match self {
+            TokenText::Inline(s) => Box::new(s.split('\n').map(move |line| {
+                let res = (start, Cow::Borrowed(line));
+                start += line.len() + 1;
+                res
+            })),
+            TokenText::Rope(r) => {
+                // unlike `str::split`, there is no empty line after a trailing newline
+                let trailing = r.len() == 0 || r.byte_at(r.len() - 1) == b'\n';
+                let lines = r.lines_raw(..).map(move |line| {
+                    let at = start;
+                    start += line.len();
+                    let line = match line {
+                        Cow::Borrowed(l) if l.ends_with('\n') => Cow::Borrowed(&l[..l.len() - 1]),
+                        Cow::Owned(mut l) if l.ends_with('\n') => {
+                            l.pop();
+                            Cow::Owned(l)
+                        },
+                        l => l
+                    };
+                    (at, line)
+                });
+                Box::new(lines.chain(if trailing { Some((r.len(), Cow::Borrowed(""))) } else { None }))
+            }
+        }
+    }
+
+    /// the text around `range` and where it starts, which is all of an inline text
+    pub fn window(&self, range: Range<usize>) -> (usize, Cow<str>) {
+        match self {
+            TokenText::Inline(s) => (0, Cow::Borrowed(s)),
+            TokenText::Rope(r) => {
+                let start = self.snap(range.start.saturating_sub(WINDOW));
+                let end = r.at_or_next_codepoint_boundary((range.end + WINDOW).min(r.len())).unwrap_or(r.len());
+                (start, r.slice_to_cow(start..end))
+            }
+        }
+    }
+
+    /// the char boundary at or before `offset`
+    fn snap(&self, offset: usize) -> usize {
+        let offset = offset.min(self.len());
+        match self {
+            TokenText::Inline(s) => (0..=offset).rev().find(|&i| s.is_char_boundary(i)).unwrap_or(0),
+            TokenText::Rope(r) => r.at_or_prev_codepoint_boundary(offset).unwrap_or(0)
+        }
+    }
+
+    /// `f` gets the text around `offset` and where it starts, which is all of an inline text
+    fn with_window<R>(&self, offset: usize, f: impl FnOnce(usize, &String) -> R) -> R {
+        match self {
+            TokenText::Inline(s) => f(0, s),
+            TokenText::Rope(_) => {
+                let (start, window) = self.window(offset..offset);
+                f(start, &window.into_owned())
+            }
+        }
+    }
+
+    /// see `Selection::constrain_to`
+    pub fn constrain(&self, selection: Selection) -> Selection {
+        match self {
+            TokenText::Inline(s) => selection.constrain_to(s),
+            TokenText::Rope(_) => Selection::new(self.snap(selection.start), self.snap(selection.end))
+        }
+    }
+
+    /// see `druid::text::movement`, in a rope the caret moves at most to the edge of the window
+    pub fn movement(&self, m: Movement, selection: Selection, modify: bool) -> Selection {
+        match self {
+            TokenText::Inline(s) => movement(m, selection, s, modify),
+            TokenText::Rope(_) => {
+                let end = self.with_window(selection.end, |start, s| {
+                    start + movement(m, Selection::caret(selection.end - start), s, false).end
+                });
+                Selection::new(if modify { selection.start } else { end }, end)
+            }
+        }
+    }
+
+    /// where backspace at `caret` deletes from
+    pub fn offset_for_delete_backwards(&self, caret: usize) -> usize {
+        self.with_window(caret, |start, s| start + offset_for_delete_backwards(&Selection::caret(caret - start), s))
+    }
+
+    pub fn next_grapheme_offset(&self, offset: usize) -> Option<usize> {
+        self.with_window(offset, |start, s| s.next_grapheme_offset(offset - start).map(|o| start + o))
+    }
+
+    /// the grapheme boundary at or before `offset`
+    pub fn grapheme_boundary(&self, offset: usize) -> usize {
+        self.with_window(offset, |start, s| {
+            s.grapheme_indices(true).map(|(i, _)| start + i).chain(std::iter::once(start + s.len()))
+                .take_while(|&i| i <= offset)
+                .last()
+                .unwrap_or(start)
+        })
+    }
+}
+
+impl From<String> for TokenText {
+    fn from(str: String) -> TokenText {
+        TokenText::new(str)
+    }
+}
+
+impl From<&str> for TokenText {
+    fn from(str: &str) -> TokenText {
+        TokenText::new(String::from(str))
+    }
+}
+
+impl PartialEq for TokenText {
+    fn eq(&self, other: &TokenText) -> bool {
+        self.len() == other.len() && self.as_str() == other.as_str()
+    }
+}
+
+impl fmt::Display for TokenText {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.as_str())
+    }
+}
+
+/// stays the same while the token is edited, a copy of a token should get a new one
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TokenId(u64);
+
+static NEXT_TOKEN_ID: AtomicU64 = AtomicU64::new(0);
+
+impl TokenId {
+    pub fn next() -> TokenId {
+        TokenId(NEXT_TOKEN_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// a tree node by its type and its first and last token, so it stays the same while it is edited inside
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId {
+    pub kind: u16,
+    pub first: TokenId,
+    pub last: TokenId
+}
 
-// TODO support large constants by using rope
 #[derive(Clone, Debug)]
 pub struct Token {
+    pub id: TokenId,
     pub tp: u16,
-    pub str: String
+    pub str: TokenText,
+    /// a placeholder of a missing token, the parser sees it as a token of `tp`, the text is empty
+    pub hole: bool
 }
 
 impl Token {
     pub fn new(tp: u16, str: &'static str) -> Token {
-        Token { tp, str: String::from(str) }
+        Token { id: TokenId::next(), tp, str: TokenText::from(str), hole: false }
+    }
+
+    pub fn hole(tp: u16) -> Token {
+        Token { id: TokenId::next(), tp, str: TokenText::from(""), hole: true }
+    }
+
+    /// the same token with a new id
+    pub fn copy(&self) -> Token {
+        Token { id: TokenId::next(), ..self.clone() }
     }
 }
 
-// TODO are vectors really have fast clone?
+// TODO are vectors really have fast clone? see the `clone` benchmark
 pub type Tokens = Vector<Token>;
+
+/// a kind buffer is split in chunks about this long
+const CHUNK: usize = 4096;
+
+/// token types as the bytes tree-sitter parses, one byte per token. it is kept in chunks along the tokens, so an
+/// edit only copies the chunks it touches
+#[derive(Clone, Debug)]
+pub struct Kinds {
+    chunks: Vec<Vec<u8>>,
+    /// where each chunk starts
+    starts: Vec<usize>,
+    len: usize
+}
+
+impl Kinds {
+    pub fn new(tokens: &Tokens) -> Kinds {
+        let bytes: Vec<u8> = tokens.iter().map(|t| t.tp as u8).collect();
+        let mut kinds = Kinds { chunks: bytes.chunks(CHUNK).map(|c| c.to_vec()).collect(), starts: vec![], len: bytes.len() };
+        if kinds.chunks.is_empty() {
+            kinds.chunks.push(vec![]);
+        }
+        kinds.update_starts(0);
+        kinds
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn update_starts(&mut self, from: usize) {
+        self.starts.truncate(from);
+        let mut start = self.starts.last().map_or(0, |&s| s + self.chunks[from - 1].len());
+        for chunk in &self.chunks[from..] {
+            self.starts.push(start);
+            start += chunk.len();
+        }
+    }
+
+    /// the chunk containing `offset`, the end is in the last chunk
+    fn chunk_index(&self, offset: usize) -> usize {
+        match self.starts.binary_search(&offset) {
+            Ok(i) => i,
+            Err(i) => i - 1
+        }
+    }
+
+    /// `start..end` is replaced by the types of `tokens[start..new_end]`, like `Tree::edit`
+    pub fn edit(&mut self, start: usize, end: usize, new_end: usize, tokens: &Tokens) {
+        let new: Vec<u8> = tokens.skip(start).take(new_end - start).iter().map(|t| t.tp as u8).collect();
+        let (i, j) = (self.chunk_index(start), self.chunk_index(end));
+        let (si, sj) = (self.starts[i], self.starts[j]);
+        if i == j {
+            self.chunks[i].splice(start - si..end - si, new);
+        } else {
+            let mut chunk = self.chunks[i][..start - si].to_vec();
+            chunk.extend(new);
+            chunk.extend(&self.chunks[j][end - sj..]);
+            self.chunks.splice(i..=j, std::iter::once(chunk));
+        }
+        if self.chunks[i].len() > CHUNK * 2 {
+            let chunk = self.chunks.remove(i);
+            self.chunks.splice(i..i, chunk.chunks(CHUNK).map(|c| c.to_vec()));
+        } else if self.chunks[i].is_empty() && self.chunks.len() > 1 {
+            self.chunks.remove(i);
+        }
+        self.len = self.len + new_end - end;
+        self.update_starts(i);
+    }
+
+    /// the rest of the chunk at `offset`, for `Parser::parse_with`
+    pub fn chunk_at(&self, offset: usize) -> &[u8] {
+        if offset >= self.len {
+            return &[];
+        }
+        let i = self.chunk_index(offset);
+        &self.chunks[i][offset - self.starts[i]..]
+    }
+}
+
+/// the index is rebuilt after this many edits
+const INDEX_EDITS: usize = 256;
+
+/// token indexes by id, built on lookup, an index found there goes through the edits since
+pub struct TokenIndex {
+    indexes: Option<HashMap<TokenId, usize>>,
+    /// tokens `start..end` are replaced by `start..new_end`
+    edits: Vec<(usize, usize, usize)>
+}
+
+impl TokenIndex {
+    pub fn new() -> TokenIndex {
+        TokenIndex { indexes: None, edits: vec![] }
+    }
+
+    /// tokens `start..end` are replaced by `start..new_end`
+    pub fn edit(&mut self, start: usize, end: usize, new_end: usize) {
+        if self.indexes.is_some() {
+            if self.edits.len() >= INDEX_EDITS {
+                self.reset();
+            } else {
+                self.edits.push((start, end, new_end));
+            }
+        }
+    }
+
+    /// the tokens are changed all over the place
+    pub fn reset(&mut self) {
+        self.indexes = None;
+        self.edits.clear();
+    }
+
+    /// the index of a token, `None` once it is removed
+    pub fn get(&mut self, tokens: &Tokens, id: TokenId) -> Option<usize> {
+        if let Some(indexes) = &self.indexes {
+            let shifted = indexes.get(&id).and_then(|&i| self.edits.iter().try_fold(i, |i, &(_, end, new_end)| {
+                if i >= end {
+                    Some(i - end + new_end)
+                } else if i >= new_end {
+                    None
+                } else {
+                    Some(i)
+                }
+            }));
+            match shifted {
+                Some(i) if tokens.get(i).map_or(false, |t| t.id == id) => return Some(i),
+                // nothing is inserted since the index is built
+                None if self.edits.is_empty() => return None,
+                // removed, or replaced by a new token
+                _ => {}
+            }
+        }
+        let indexes: HashMap<TokenId, usize> = tokens.iter().enumerate().map(|(i, t)| (t.id, i)).collect();
+        let index = indexes.get(&id).cloned();
+        self.indexes = Some(indexes);
+        self.edits.clear();
+        index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_rope(text: &TokenText) -> bool {
+        match text {
+            TokenText::Inline(_) => false,
+            TokenText::Rope(_) => true
+        }
+    }
+
+    #[test]
+    fn edit_to_rope_above_large() {
+        let mut text = TokenText::from("a".repeat(LARGE - 1));
+        text.edit(0..0, "b");
+        assert!(!is_rope(&text));
+        text.edit(LARGE..LARGE, "c");
+        assert!(is_rope(&text));
+        assert_eq!(text.len(), LARGE + 1);
+        assert_eq!(text.slice(LARGE - 1..LARGE + 1), "ac");
+    }
+
+    #[test]
+    fn edit_to_inline_below_half() {
+        let mut text = TokenText::from("a".repeat(LARGE + 1));
+        assert!(is_rope(&text));
+        text.edit(0..LARGE + 1 - LARGE / 2, "");
+        assert_eq!(text.len(), LARGE / 2);
+        assert!(is_rope(&text));
+        text.edit(0..1, "");
+        assert!(!is_rope(&text));
+        assert_eq!(text.as_str(), "a".repeat(LARGE / 2 - 1));
+    }
+
+    /// 3 byte chars, so the window edges are inside a char
+    fn euros() -> TokenText {
+        TokenText::from("€".repeat(LARGE))
+    }
+
+    #[test]
+    fn window_snaps_to_chars() {
+        let text = euros();
+        let (start, window) = text.window(1200..1200);
+        assert_eq!(start, 1200 - WINDOW - 2);
+        assert_eq!(start + window.len(), 1200 + WINDOW + 2);
+    }
+
+    #[test]
+    fn movement_at_window_edges() {
+        let text = euros();
+        assert_eq!(text.movement(Movement::Left, Selection::caret(1200), false).end, 1197);
+        assert_eq!(text.movement(Movement::Right, Selection::caret(1200), false).end, 1203);
+        let end = text.len();
+        assert_eq!(text.movement(Movement::Right, Selection::caret(end - 3), false).end, end);
+        assert_eq!(text.movement(Movement::Right, Selection::caret(end), false).end, end);
+        assert_eq!(text.movement(Movement::Left, Selection::caret(3), false).end, 0);
+        assert_eq!(text.movement(Movement::Left, Selection::caret(0), false).end, 0);
+        let selection = text.movement(Movement::Right, Selection::new(30, 1200), true);
+        assert_eq!((selection.start, selection.end), (30, 1203));
+    }
+
+    #[test]
+    fn delete_backwards_at_window_edges() {
+        let text = euros();
+        assert_eq!(text.offset_for_delete_backwards(1200), 1197);
+        assert_eq!(text.offset_for_delete_backwards(3), 0);
+        assert_eq!(text.offset_for_delete_backwards(text.len()), text.len() - 3);
+    }
+
+    fn lines(text: &TokenText) -> Vec<(usize, String)> {
+        text.lines().map(|(i, l)| (i, l.into_owned())).collect()
+    }
+
+    fn split(text: &str) -> Vec<(usize, String)> {
+        let mut start = 0;
+        text.split('\n').map(|l| {
+            let res = (start, String::from(l));
+            start += l.len() + 1;
+            res
+        }).collect()
+    }
+
+    #[test]
+    fn lines_like_split() {
+        for text in &["", "a", "a\nb", "a\nb\n", "\n\n"] {
+            assert_eq!(lines(&TokenText::from(*text)), split(text));
+        }
+        let large = format!("{}\n", "x".repeat(100)).repeat(1000);
+        for text in &[large.clone(), format!("{}y", large), format!("\n{}", large)] {
+            let token = TokenText::from(text.clone());
+            assert!(is_rope(&token));
+            assert_eq!(lines(&token), split(text));
+        }
+    }
+
+    fn document(len: usize) -> Tokens {
+        (0..len).map(|i| Token::new((i % 200) as u16, "")).collect()
+    }
+
+    /// `start..end` is replaced by `new` tokens, in the tokens and in the kinds
+    fn edit(kinds: &mut Kinds, tokens: &mut Tokens, start: usize, end: usize, new: usize) {
+        let tail = tokens.split_off(end);
+        tokens.truncate(start);
+        for _ in 0..new {
+            tokens.push_back(Token::new(250, ""));
+        }
+        tokens.append(tail);
+        kinds.edit(start, end, start + new, tokens);
+    }
+
+    /// all bytes like tree-sitter reads them
+    fn read(kinds: &Kinds) -> Vec<u8> {
+        let mut res = vec![];
+        loop {
+            let chunk = kinds.chunk_at(res.len());
+            if chunk.is_empty() {
+                return res;
+            }
+            res.extend_from_slice(chunk);
+        }
+    }
+
+    /// the chunks read the same as the kinds built from scratch
+    fn check(kinds: &Kinds, tokens: &Tokens) {
+        let expected = read(&Kinds::new(tokens));
+        assert_eq!(expected, tokens.iter().map(|t| t.tp as u8).collect::<Vec<u8>>());
+        assert_eq!(kinds.len(), expected.len());
+        assert_eq!(read(kinds), expected);
+        for offset in (0..=expected.len()).step_by(61).chain(kinds.starts.iter().flat_map(|&s| vec![s.saturating_sub(1), s, s + 1])) {
+            let chunk = kinds.chunk_at(offset);
+            assert!(expected[offset.min(expected.len())..].starts_with(chunk));
+            assert_eq!(chunk.is_empty(), offset >= expected.len());
+        }
+        assert!(kinds.chunks.iter().all(|c| c.len() <= CHUNK * 2));
+        assert!(kinds.chunks.len() == 1 || kinds.chunks.iter().all(|c| !c.is_empty()));
+    }
+
+    fn kinds(len: usize) -> (Kinds, Tokens) {
+        let tokens = document(len);
+        (Kinds::new(&tokens), tokens)
+    }
+
+    #[test]
+    fn kinds_edit_inside_chunk() {
+        let (mut kinds, mut tokens) = kinds(CHUNK * 3);
+        edit(&mut kinds, &mut tokens, 10, 20, 5);
+        check(&kinds, &tokens);
+        edit(&mut kinds, &mut tokens, CHUNK + 1, CHUNK + 1, 3);
+        check(&kinds, &tokens);
+    }
+
+    #[test]
+    fn kinds_edit_across_chunks() {
+        let (mut kinds, mut tokens) = kinds(CHUNK * 3);
+        edit(&mut kinds, &mut tokens, CHUNK - 10, CHUNK + 10, 3);
+        check(&kinds, &tokens);
+        let len = tokens.len();
+        edit(&mut kinds, &mut tokens, 5, len - 5, 1);
+        check(&kinds, &tokens);
+    }
+
+    #[test]
+    fn kinds_chunk_split() {
+        let (mut kinds, mut tokens) = kinds(CHUNK * 2);
+        edit(&mut kinds, &mut tokens, 100, 100, CHUNK * 2);
+        check(&kinds, &tokens);
+        assert!(kinds.chunks.len() > 2);
+    }
+
+    #[test]
+    fn kinds_chunk_empty() {
+        let (mut kinds, mut tokens) = kinds(CHUNK * 3);
+        edit(&mut kinds, &mut tokens, CHUNK * 2, CHUNK * 3, 0);
+        check(&kinds, &tokens);
+        edit(&mut kinds, &mut tokens, CHUNK, CHUNK * 2, 0);
+        check(&kinds, &tokens);
+        edit(&mut kinds, &mut tokens, 0, CHUNK, 0);
+        check(&kinds, &tokens);
+        edit(&mut kinds, &mut tokens, 0, 0, 2);
+        check(&kinds, &tokens);
+    }
+
+    #[test]
+    fn kinds_edit_at_len() {
+        let (mut kinds, mut tokens) = kinds(CHUNK * 2 + 7);
+        let len = tokens.len();
+        edit(&mut kinds, &mut tokens, len, len, 5);
+        check(&kinds, &tokens);
+        let len = tokens.len();
+        edit(&mut kinds, &mut tokens, len - 3, len, 0);
+        check(&kinds, &tokens);
+    }
+
+    #[test]
+    fn kinds_edits() {
+        let (mut kinds, mut tokens) = kinds(CHUNK * 4);
+        let mut seed: usize = 7;
+        let mut random = |n: usize| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) % n
+        };
+        for _ in 0..200 {
+            let start = random(tokens.len() + 1);
+            let end = start + random((tokens.len() - start).min(CHUNK * 2) + 1);
+            let new = random(CHUNK);
+            edit(&mut kinds, &mut tokens, start, end, new);
+            check(&kinds, &tokens);
+        }
+    }
+
+    #[test]
+    fn token_index_through_edits() {
+        let mut tokens: Tokens = (0..4).map(|_| Token::new(1, "a")).collect();
+        let ids: Vec<TokenId> = tokens.iter().map(|t| t.id).collect();
+        let mut index = TokenIndex::new();
+        assert_eq!(index.get(&tokens, ids[2]), Some(2));
+        tokens.insert(0, Token::new(1, "b"));
+        index.edit(0, 0, 1);
+        assert_eq!(index.get(&tokens, ids[2]), Some(3));
+        // replaced in place
+        tokens[1] = Token::new(1, "c");
+        index.edit(1, 2, 2);
+        assert_eq!(index.get(&tokens, ids[0]), None);
+        assert_eq!(index.get(&tokens, ids[1]), Some(2));
+        tokens.remove(2);
+        index.edit(2, 3, 2);
+        assert_eq!(index.get(&tokens, ids[1]), None);
+        assert_eq!(index.get(&tokens, ids[3]), Some(3));
+        index.reset();
+        assert_eq!(index.get(&tokens, ids[3]), Some(3));
+    }
+}
@@ -0,0 +1,76 @@
+use crate::*;
+use crate::editor::expected_at;
+
+/// completion popup state, the candidates are token types
+pub struct Completion {
+    pub token: usize,
+    pub candidates: Vec<u16>,
+    pub selected: usize
+}
+
+impl Completion {
+    /// constants valid at the position which starts with the token text, and regex tokens accepting the text, or by
+    /// name when they can be empty, as the text is cleared then
+    pub fn new(language: &Language, tokens: &Tokens, token: usize) -> Option<Completion> {
+        let text = tokens[token].str.as_str();
+        let prefix: &str = &text;
+        let candidates: Vec<u16> = expected_at(language, tokens, token).iter().cloned().filter(|&t| match language.node(t) {
+            NodeSpec::Token(TokenSpec::Constant { str, .. }) => str.starts_with(prefix),
+            NodeSpec::Token(spec) => spec.accept(prefix) || (spec.can_empty() && spec.name().starts_with(prefix)),
+            _ => false
+        }).collect();
+        if candidates.is_empty() {
+            None
+        } else {
+            Some(Completion { token, candidates, selected: 0 })
+        }
+    }
+
+    pub fn select(&mut self, delta: isize) {
+        let len = self.candidates.len() as isize;
+        self.selected = ((self.selected as isize + delta) % len + len) as usize % len as usize;
+    }
+
+    pub fn current(&self) -> u16 {
+        self.candidates[self.selected]
+    }
+}
+
+/// how a candidate is shown
+pub fn candidate_label(language: &Language, tp: u16) -> String {
+    match language.node(tp) {
+        NodeSpec::Token(TokenSpec::Constant { str, .. }) => String::from(*str),
+        NodeSpec::Token(spec) => format!("⟨{}⟩", spec.name()),
+        _ => String::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use druid::im::vector;
+    use crate::languages::json::INSTANCE as JSON;
+
+    fn candidates(text: &'static str) -> Vec<u16> {
+        let tokens = vector![Token::new(5, "["), Token::new(JSON.lex_error(), text), Token::new(6, "]")];
+        Completion::new(&JSON, &tokens, 1).map_or(vec![], |c| c.candidates)
+    }
+
+    #[test]
+    fn empty_token() {
+        // a number can't be empty
+        assert_eq!(candidates(""), vec![1, 5, 6, 7, 9, 10, 11]);
+    }
+
+    #[test]
+    fn constant_prefix() {
+        assert_eq!(candidates("nu"), vec![7, 11]);
+    }
+
+    #[test]
+    fn regex_by_name() {
+        // the text would be cleared, and a number can't be empty
+        assert_eq!(candidates("num"), vec![7]);
+        assert_eq!(candidates("-1"), vec![7, 8]);
+    }
+}
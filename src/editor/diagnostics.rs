@@ -0,0 +1,203 @@
+use std::ops::Range;
+use std::collections::BTreeMap;
+use tree_sitter::{Node, Tree};
+
+use crate::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    /// token span
+    pub range: Range<usize>,
+    pub severity: Severity,
+    pub message: String,
+    /// token types would be valid at the start of the range
+    pub expected: Vec<u16>
+}
+
+impl Diagnostic {
+    pub fn contains(&self, token: usize) -> bool {
+        self.range.contains(&token)
+    }
+}
+
+/// computed from lex errors, holes and tree-sitter error and missing nodes, sorted by position
+pub fn diagnostics(language: &Language, tokens: &Tokens, tree: &Tree) -> Vec<Diagnostic> {
+    merge(&token_diagnostics(language, tokens, 0..tokens.len()), syntax_diagnostics(language, tokens, tree))
+}
+
+/// holes and lex errors of the tokens in `range`, sorted. a lex error depends on the token before it too
+pub fn token_diagnostics(language: &Language, tokens: &Tokens, range: Range<usize>) -> Vec<Diagnostic> {
+    let mut res = vec![];
+    let start = range.start;
+    for (i, token) in tokens.skip(start).take(range.end - start).iter().enumerate() {
+        let i = start + i;
+        if token.hole {
+            res.push(Diagnostic {
+                range: i..i + 1,
+                severity: Severity::Warning,
+                message: format!("missing {}", token_name(language, token.tp)),
+                expected: vec![token.tp]
+            });
+        } else if token.tp == language.lex_error() && !token.str.is_empty() {
+            res.push(Diagnostic {
+                range: i..i + 1,
+                severity: Severity::Error,
+                message: format!("`{}` is not a valid token", token.str),
+                expected: expected_at(language, tokens, i).to_vec()
+            });
+        }
+    }
+    res
+}
+
+/// tree-sitter error and missing nodes, only the nodes with errors are visited
+pub fn syntax_diagnostics(language: &Language, tokens: &Tokens, tree: &Tree) -> Vec<Diagnostic> {
+    let mut res = vec![];
+    syntax_errors(language, tokens, tree.root_node(), &mut res);
+    res
+}
+
+/// sorted by position, token diagnostics first
+pub fn merge(tokens: &[Diagnostic], syntax: Vec<Diagnostic>) -> Vec<Diagnostic> {
+    let mut res = tokens.to_vec();
+    res.extend(syntax);
+    res.sort_by_key(|d| d.range.start);
+    res
+}
+
+pub fn token_name(language: &Language, tp: u16) -> String {
+    match language.node(tp) {
+        NodeSpec::Token(TokenSpec::Constant { str, .. }) => format!("`{}`", str),
+        NodeSpec::Token(t) => String::from(t.name()),
+        _ => String::from("token")
+    }
+}
+
+/// what the grammar allows after the previous token
+pub fn expected_at<'a>(language: &'a Language, tokens: &Tokens, index: usize) -> &'a [u16] {
+    let previous = if index > 0 { tokens.get(index - 1).map(|t| t.tp) } else { None };
+    language.expected_after(previous)
+}
+
+/// like "expected `,` or `}` after number"
+fn expected_message(language: &Language, tokens: &Tokens, index: usize) -> String {
+    let names: Vec<String> = expected_at(language, tokens, index).iter().map(|&t| token_name(language, t)).collect();
+    let expected = match names.split_last() {
+        None => String::from("end of document"),
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{} or {}", rest.join(", "), last)
+    };
+    if index > 0 && index <= tokens.len() {
+        format!("expected {} after {}", expected, token_name(language, tokens[index - 1].tp))
+    } else {
+        format!("expected {} at the start", expected)
+    }
+}
+
+fn syntax_errors(language: &Language, tokens: &Tokens, node: Node, res: &mut Vec<Diagnostic>) {
+    let start = node.start_byte();
+    if node.is_error() || node.is_missing() {
+        res.push(Diagnostic {
+            range: start..node.end_byte().max(start + 1),
+            severity: Severity::Error,
+            message: expected_message(language, tokens, start),
+            expected: expected_at(language, tokens, start).to_vec()
+        });
+    } else if node.has_error() {
+        let mut cursor = node.walk();
+        let mut has_child = cursor.goto_first_child();
+        while has_child {
+            syntax_errors(language, tokens, cursor.node(), res);
+            has_child = cursor.goto_next_sibling();
+        }
+    }
+}
+
+/// the most severe diagnostic on each token, built once per version so painting a token doesn't scan the diagnostics
+pub struct Severities {
+    /// the severity from a token on, until the next key
+    steps: BTreeMap<usize, Option<Severity>>
+}
+
+impl Severities {
+    pub fn new(diagnostics: &[Diagnostic]) -> Severities {
+        let mut events: Vec<(usize, Severity, bool)> = vec![];
+        for d in diagnostics {
+            events.push((d.range.start, d.severity, true));
+            events.push((d.range.end, d.severity, false));
+        }
+        events.sort_by_key(|e| e.0);
+        let mut steps = BTreeMap::new();
+        let (mut warnings, mut errors) = (0, 0);
+        for (i, &(at, severity, open)) in events.iter().enumerate() {
+            let count = if severity == Severity::Error { &mut errors } else { &mut warnings };
+            if open { *count += 1 } else { *count -= 1 }
+            if events.get(i + 1).map_or(true, |e| e.0 != at) {
+                let max = if errors > 0 { Some(Severity::Error) } else if warnings > 0 { Some(Severity::Warning) } else { None };
+                steps.insert(at, max);
+            }
+        }
+        Severities { steps }
+    }
+
+    pub fn at(&self, token: usize) -> Option<Severity> {
+        self.steps.range(..=token).next_back().and_then(|s| *s.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use druid::im::vector;
+    use crate::languages::json::INSTANCE as JSON;
+
+    fn diagnostic(range: Range<usize>, severity: Severity) -> Diagnostic {
+        Diagnostic { range, severity, message: String::new(), expected: vec![] }
+    }
+
+    #[test]
+    fn severities_overlap() {
+        let severities = Severities::new(&[
+            diagnostic(1..6, Severity::Warning),
+            diagnostic(3..4, Severity::Error),
+            diagnostic(5..6, Severity::Warning),
+            diagnostic(8..9, Severity::Error)
+        ]);
+        let at: Vec<Option<Severity>> = (0..10).map(|t| severities.at(t)).collect();
+        let (w, e) = (Some(Severity::Warning), Some(Severity::Error));
+        assert_eq!(at, vec![None, w, w, e, w, w, None, None, e, None]);
+    }
+
+    #[test]
+    fn severities_empty() {
+        assert_eq!(Severities::new(&[]).at(0), None);
+    }
+
+    fn json() -> Tokens {
+        vector![Token::new(1, "{"), Token::new(7, "key"), Token::new(4, ":"), Token::new(8, "1"), Token::new(2, ",")]
+    }
+
+    #[test]
+    fn expected_message_json() {
+        let (language, tokens) = (&*JSON, json());
+        assert_eq!(expected_message(language, &tokens, 0), "expected `{`, `[`, string, number, `true`, `false` or `null` at the start");
+        assert_eq!(expected_message(language, &tokens, 1), "expected `}` or string after `{`");
+        assert_eq!(expected_message(language, &tokens, 2), "expected `,`, `}`, `:` or `]` after string");
+        assert_eq!(expected_message(language, &tokens, 3), "expected `{`, `[`, string, number, `true`, `false` or `null` after `:`");
+        assert_eq!(expected_message(language, &tokens, 4), "expected `,`, `}` or `]` after number");
+        assert_eq!(expected_message(language, &tokens, 5), "expected `{`, `[`, string, number, `true`, `false` or `null` after `,`");
+    }
+
+    #[test]
+    fn expected_at_json() {
+        let tokens = json();
+        assert_eq!(expected_at(&JSON, &tokens, 2), JSON.expected_after(Some(7)));
+        assert_eq!(expected_at(&JSON, &tokens, 0), JSON.expected_after(None));
+    }
+}
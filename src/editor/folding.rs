@@ -0,0 +1,81 @@
+use std::ops::Range;
+use tree_sitter::{Node, Tree};
+
+use crate::*;
+
+// a fold is keyed by the token index of the start delimiter of a tree node, it is dropped once the node is gone
+
+/// see `NodeSpec::delimiters`
+fn is_tree(language: &Language, node: Node) -> bool {
+    language.node(node.kind_id()).delimiters().is_some()
+}
+
+/// the tree node whose start delimiter is token `start`
+pub fn tree_node_at<'a>(language: &Language, tree: &'a Tree, start: usize) -> Option<Node<'a>> {
+    let mut node = tree.root_node().descendant_for_byte_range(start, start + 1);
+    while let Some(n) = node {
+        if n.start_byte() != start {
+            return None;
+        }
+        if is_tree(language, n) && !n.has_error() && n.end_byte() > start + 1 {
+            return Some(n);
+        }
+        node = n.parent();
+    }
+    None
+}
+
+/// tree nodes containing token `token`, innermost first
+pub fn enclosing_trees<'a>(language: &Language, tree: &'a Tree, token: usize) -> Vec<Node<'a>> {
+    let mut res = vec![];
+    let mut node = tree.root_node().descendant_for_byte_range(token, token + 1);
+    while let Some(n) = node {
+        if is_tree(language, n) && !n.has_error() && n.end_byte() > n.start_byte() + 1 {
+            res.push(n);
+        }
+        node = n.parent();
+    }
+    res
+}
+
+/// the tokens hidden by a fold, the delimiters are still shown
+pub fn fold_range(language: &Language, tree: &Tree, start: usize) -> Option<Range<usize>> {
+    tree_node_at(language, tree, start).map(|n| start + 1..n.end_byte() - 1)
+}
+
+/// children which are not delimiters or separators
+pub fn element_count(language: &Language, node: Node) -> usize {
+    let spec = language.node(node.kind_id());
+    let sep: &[u16] = match spec {
+        NodeSpec::Tree { sep, .. } => sep,
+        _ => &[]
+    };
+    if let Some((start, end)) = spec.delimiters() {
+        let mut cursor = node.walk();
+        let mut has_child = cursor.goto_first_child();
+        let mut count = 0;
+        while has_child {
+            let kind = cursor.node().kind_id();
+            if !start.contains(&kind) && !sep.contains(&kind) && !end.contains(&kind) && !cursor.node().is_extra() {
+                count += 1;
+            }
+            has_child = cursor.goto_next_sibling();
+        }
+        count
+    } else {
+        0
+    }
+}
+
+/// start delimiters of all foldable tree nodes
+pub fn tree_nodes(language: &Language, node: Node, res: &mut Vec<usize>) {
+    if is_tree(language, node) && !node.has_error() && node.end_byte() > node.start_byte() + 1 {
+        res.push(node.start_byte());
+    }
+    let mut cursor = node.walk();
+    let mut has_child = cursor.goto_first_child();
+    while has_child {
+        tree_nodes(language, cursor.node(), res);
+        has_child = cursor.goto_next_sibling();
+    }
+}
@@ -1,37 +1,168 @@
-use crate::Token;
+use std::collections::HashMap;
+use std::ops::Range;
 use druid::piet::{TextLayout, PietTextLayout};
+use druid::Point;
+use crate::Margin;
+use crate::editor::{shaped_position, text_offset};
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hit {
+    /// token index and text offset
+    Token(usize, usize),
+    /// the placeholder of a missing token of type, which would be inserted at index
+    Missing(usize, u16),
+    /// the placeholder of a folded tree node, by its start delimiter
+    Fold(usize),
+}
+
+/// a token is laid out as one or more fragments, a fragment shows the `start..end` bytes of the token text
 pub struct TokenLayout {
-    token: Token,
+    index: usize,
+    tp: u16,
+    /// the semantic category in context
+    category: &'static str,
+    /// for a delimiter, how many tree nodes it is in, `None` when it is unmatched
+    nesting: Option<usize>,
+    start: usize,
+    end: usize,
     margin_left: f64,
     margin_right: f64,
     is_separator: bool,
+    /// inside a tree-sitter ERROR node
+    error: bool,
+    /// a placeholder of a missing token, it is not in the tokens, `index` is where it would be inserted
+    missing: bool,
+    /// a placeholder token in the tokens, see `Token::hole`
+    hole: bool,
+    /// the placeholder of a folded tree node, `index` is the start delimiter
+    fold: bool,
+    /// see `Shaped`
+    map: Vec<(usize, usize)>,
+    /// IME pre-edit text in the layout, it is not in the token
+    preedit: Option<(usize, usize)>,
     layout: PietTextLayout,
 }
 
+/// see the fields of `TokenLayout`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokenFlags {
+    pub is_separator: bool,
+    pub error: bool,
+    pub missing: bool,
+    pub hole: bool
+}
+
 impl TokenLayout {
-    pub fn new(
-        token: Token,
-        margin_left: f64,
-        margin_right: f64,
-        is_separator: bool,
-        layout: PietTextLayout
-    ) -> TokenLayout {
+    /// `range` is the text of the token in this fragment
+    pub fn new(index: usize, tp: u16, category: &'static str, range: Range<usize>, margin: &Margin, flags: TokenFlags, layout: PietTextLayout) -> TokenLayout {
+        let TokenFlags { is_separator, error, missing, hole } = flags;
+        TokenLayout {
+            index, tp, category, nesting: None, start: range.start, end: range.end, margin_left: margin.left, margin_right: margin.right,
+            is_separator, error, missing, hole, fold: false, map: vec![], preedit: None, layout
+        }
+    }
+
+    /// see `nesting`
+    pub fn with_nesting(mut self, nesting: Option<usize>) -> TokenLayout {
+        self.nesting = nesting;
+        self
+    }
+
+    pub fn fold(index: usize, margin_left: f64, margin_right: f64, layout: PietTextLayout) -> TokenLayout {
         TokenLayout {
-            token, margin_left, margin_right, is_separator, layout
+            index, tp: 0, category: "", nesting: None, start: 0, end: 0, margin_left, margin_right, is_separator: false,
+            error: false, missing: false, hole: false, fold: true, map: vec![], preedit: None, layout
         }
     }
+
+    /// `layout` is of the shaped text instead of the text of the fragment
+    pub fn with_shaped(mut self, map: Vec<(usize, usize)>, preedit: Option<(usize, usize)>) -> TokenLayout {
+        self.map = map;
+        self.preedit = preedit;
+        self
+    }
     pub fn layout(&self) -> &PietTextLayout {
         &self.layout
     }
     pub fn width(&self) -> f64 {
         self.layout.width() // TODO trailing whitespace not included
     }
+    pub fn index(&self) -> usize {
+        self.index
+    }
     pub fn tp(&self) -> u16 {
-        self.token.tp
+        self.tp
+    }
+    pub fn category(&self) -> &'static str {
+        self.category
+    }
+    pub fn nesting(&self) -> Option<usize> {
+        self.nesting
     }
     pub fn is_empty(&self) -> bool {
-        self.token.str.is_empty()
+        self.start == self.end
+    }
+    pub fn is_error(&self) -> bool {
+        self.error
+    }
+    pub fn is_missing(&self) -> bool {
+        self.missing
+    }
+    pub fn is_hole(&self) -> bool {
+        self.hole
+    }
+    pub fn is_fold(&self) -> bool {
+        self.fold
+    }
+    pub fn has_preedit(&self) -> bool {
+        self.preedit.is_some()
+    }
+
+    /// the x range of the pre-edit text
+    pub fn preedit_x(&self) -> Option<(f64, f64)> {
+        let (start, end) = self.preedit?;
+        let (x0, x1) = (self.layout_x(start), self.layout_x(end));
+        Some((x0.min(x1), x0.max(x1)))
+    }
+
+    fn layout_x(&self, pos: usize) -> f64 {
+        self.layout.hit_test_text_position(pos).map_or(self.width(), |h| h.point.x)
+    }
+
+    /// the x offset of text `offset` of token `token`, if it is inside this fragment
+    pub fn offset_x(&self, token: usize, offset: usize) -> Option<f64> {
+        if self.missing || self.fold || self.index != token || offset < self.start || offset > self.end {
+            None
+        } else if self.is_empty() && self.preedit.is_none() {
+            Some(0.0)
+        } else {
+            Some(self.layout_x(shaped_position(&self.map, offset - self.start)))
+        }
+    }
+
+    /// the x range of text `start..end` of token `token` clipped to this fragment
+    pub fn range_x(&self, token: usize, start: usize, end: usize) -> Option<(f64, f64)> {
+        let (start, end) = (start.max(self.start), end.min(self.end));
+        if start >= end {
+            return None;
+        }
+        // in right-to-left text the end can be on the left
+        let (x0, x1) = (self.offset_x(token, start)?, self.offset_x(token, end)?);
+        Some((x0.min(x1), x0.max(x1)))
+    }
+
+    /// `x` is relative to the start of this fragment
+    pub fn hit_test(&self, x: f64) -> Hit {
+        if self.missing {
+            Hit::Missing(self.index, self.tp)
+        } else if self.fold {
+            Hit::Fold(self.index)
+        } else if self.is_empty() {
+            Hit::Token(self.index, self.start)
+        } else {
+            let pos = self.layout.hit_test_point(Point::new(x, 0.0)).metrics.text_position;
+            Hit::Token(self.index, (self.start + text_offset(&self.map, pos)).min(self.end))
+        }
     }
 }
 
@@ -57,11 +188,34 @@ impl Line {
     pub fn indent(&self) -> f64 { self.indent }
     pub fn ascent(&self) -> f64 { self.ascent }
     pub fn descent(&self) -> f64 { self.descent }
+    pub fn height(&self) -> f64 { self.ascent + self.descent }
     pub fn tokens(&self) -> &Vec<(f64, TokenLayout)> { &self.tokens }
 
+    /// tokens with their x position
+    pub fn positions(&self) -> impl Iterator<Item=(f64, &TokenLayout)> {
+        let mut left = self.indent;
+        self.tokens.iter().map(move |(margin, t)| {
+            left += margin;
+            let x = left;
+            left += t.width();
+            (x, t)
+        })
+    }
+
+    pub fn hit_test(&self, x: f64) -> Option<Hit> {
+        let mut hit: Option<(f64, &TokenLayout)> = None;
+        for (x0, t) in self.positions() {
+            if hit.is_none() || x >= x0 {
+                hit = Some((x0, t));
+            }
+        }
+        hit.map(|(x0, t)| t.hit_test(x - x0))
+    }
+
     pub fn single(token: TokenLayout) -> Line {
-        let width = token.width();
-        Line { indent: 0.0, tokens: vec![(0.0, token)], width, ascent: 0.0, descent: 0.0 }
+        let mut line = Line::new();
+        line.push(token);
+        line
     }
 
     pub fn is_empty(&self) -> bool {
@@ -91,14 +245,18 @@ impl Line {
         }
     }
 
+    pub fn first(&self) -> Option<&TokenLayout> {
+        self.tokens.first().map(|n| &n.1)
+    }
+
     pub fn last(&self) -> Option<&TokenLayout> {
         self.tokens.last().map(|n| &n.1)
     }
 
 
     pub fn push(&mut self, t: TokenLayout) {
-        if !t.token.str.is_empty() {
-            let metrics = t.layout.line_metric(0).unwrap();
+        // fragments are always laid out as a single text line, empty ones by a placeholder text
+        if let Some(metrics) = t.layout.line_metric(0) {
             self.ascent = self.ascent.max(metrics.baseline);
             self.descent = self.descent.max(metrics.height - metrics.baseline);
         }
@@ -220,3 +378,97 @@ impl LayoutResult {
     }
 }
 
+
+/// line index and x position of the cursor, when a offset is shared by two fragments, the later one wins
+pub fn cursor_position(lines: &[Line], token: usize, offset: usize) -> Option<(usize, f64)> {
+    let mut res = None;
+    for (i, line) in lines.iter().enumerate() {
+        for (x, t) in line.positions() {
+            if let Some(dx) = t.offset_x(token, offset) {
+                res = Some((i, x + dx));
+            }
+        }
+    }
+    res
+}
+
+pub fn line_top(lines: &[Line], line: usize) -> f64 {
+    lines.iter().take(line).map(|l| l.height()).sum()
+}
+
+/// the top of every line, and the bottom of the last one
+pub fn line_tops(lines: &[Line]) -> Vec<f64> {
+    let mut tops = Vec::with_capacity(lines.len() + 1);
+    let mut top = 0.0;
+    tops.push(top);
+    for line in lines {
+        top += line.height();
+        tops.push(top);
+    }
+    tops
+}
+
+/// the line at `y`, the last line extends downward
+pub fn line_at(lines: &[Line], y: f64) -> Option<usize> {
+    let mut top = 0.0;
+    for (i, line) in lines.iter().enumerate() {
+        top += line.height();
+        if y < top || i == lines.len() - 1 {
+            return Some(i);
+        }
+    }
+    None
+}
+
+pub fn hit_test(lines: &[Line], point: Point) -> Option<Hit> {
+    let mut top = 0.0;
+    for (i, line) in lines.iter().enumerate() {
+        top += line.height();
+        if point.y < top || i == lines.len() - 1 {
+            return line.hit_test(point.x);
+        }
+    }
+    None
+}
+
+/// the token exactly under the point, unlike `hit_test` which finds the nearest one
+pub fn token_at(lines: &[Line], point: Point) -> Option<&TokenLayout> {
+    let mut top = 0.0;
+    for line in lines {
+        if point.y >= top && point.y < top + line.height() {
+            return line.positions().find(|(x, t)| point.x >= *x && point.x < x + t.width()).map(|n| n.1);
+        }
+        top += line.height();
+    }
+    None
+}
+
+/// a vertical line between the start and end delimiter of a tree node laid out in multiple lines
+pub struct Guide {
+    /// the start delimiter
+    pub start: usize,
+    pub x: f64,
+    pub lines: Range<usize>
+}
+
+/// `delimiters` are the start and end tokens of tree nodes, a guide is at the indent of the line of the start
+pub fn guides(lines: &[Line], delimiters: &[(usize, usize)]) -> Vec<Guide> {
+    let mut first_line: HashMap<usize, usize> = HashMap::new();
+    for (i, line) in lines.iter().enumerate() {
+        for (_, t) in line.positions() {
+            if !t.is_missing() && !t.is_fold() {
+                first_line.entry(t.index()).or_insert(i);
+            }
+        }
+    }
+    delimiters.iter().filter_map(|&(start, end)| {
+        let (l0, l1) = (*first_line.get(&start)?, *first_line.get(&end)?);
+        // a hanging end delimiter is on the last line of the children
+        let l1 = if lines[l1].first().map(|t| t.index()) == Some(end) { l1 } else { l1 + 1 };
+        if l1 > l0 + 1 {
+            Some(Guide { start, x: lines[l0].indent(), lines: l0 + 1..l1 })
+        } else {
+            None
+        }
+    }).collect()
+}
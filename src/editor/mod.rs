@@ -1,3 +1,11 @@
+pub mod state; pub use state::*;
 pub mod widget; pub use widget::*;
 pub mod layout; pub use layout::*;
 
+pub mod diagnostics; pub use diagnostics::*;
+pub mod completion; pub use completion::*;
+pub mod search; pub use search::*;
+pub mod structural; pub use structural::*;
+pub mod folding; pub use folding::*;
+pub mod theme; pub use theme::*;
+pub mod shaping; pub use shaping::*;
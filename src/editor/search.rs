@@ -0,0 +1,300 @@
+use std::ops::Range;
+use std::cmp::Ordering;
+use regex::Regex;
+use tree_sitter::{Node, Tree};
+use druid::text::EditAction;
+
+use crate::*;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Match {
+    pub token: usize,
+    /// bytes in the token text
+    pub range: Range<usize>
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SearchQuery {
+    pub text: String,
+    pub regex: bool,
+    /// the text is a tree-sitter query, see `find_tree_matches`
+    pub structural: bool,
+    /// index into the search scopes of the language
+    pub scope: usize
+}
+
+impl SearchQuery {
+    /// plain text is searched as an escaped regex
+    pub fn matcher(&self) -> Result<Regex, String> {
+        if self.regex {
+            Regex::new(&self.text).map_err(|e| e.to_string())
+        } else {
+            Regex::new(&regex::escape(&self.text)).map_err(|e| e.to_string())
+        }
+    }
+}
+
+/// the leaf node of a token
+pub fn token_node(tree: &Tree, index: usize) -> Option<Node> {
+    tree.root_node().descendant_for_byte_range(index, index + 1)
+}
+
+/// non-empty matches inside the text of tokens in the scope, holes are never matched
+pub fn find_matches(language: &Language, tokens: &Tokens, tree: &Tree, query: &SearchQuery) -> Result<Vec<Match>, String> {
+    if query.text.is_empty() {
+        return Ok(vec![]);
+    }
+    let re = query.matcher()?;
+    let pattern = language.search_scopes().get(query.scope).map(|s| &s.pattern);
+    let mut res = vec![];
+    for (i, token) in tokens.iter().enumerate() {
+        if token.hole || !pattern.map_or(true, |p| p.matches(token.tp, || token_node(tree, i))) {
+            continue;
+        }
+        for m in re.find_iter(&token.str.as_str()) {
+            if m.start() < m.end() {
+                res.push(Match { token: i, range: m.start()..m.end() });
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// replace the matches starting at `starts`, `$1` like groups are expanded in regex mode
+pub fn replace_matches(re: &Regex, expand: bool, text: &str, replacement: &str, starts: &[usize]) -> String {
+    let mut res = String::new();
+    let mut last = 0;
+    for caps in re.captures_iter(text) {
+        let m = caps.get(0).unwrap();
+        if m.start() == m.end() || !starts.contains(&m.start()) {
+            continue;
+        }
+        res.push_str(&text[last..m.start()]);
+        if expand {
+            caps.expand(replacement, &mut res);
+        } else {
+            res.push_str(replacement);
+        }
+        last = m.end();
+    }
+    res.push_str(&text[last..]);
+    res
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SearchField {
+    Query,
+    Replace
+}
+
+/// the find and replace panel, the matches are refreshed when the query or the document is changed
+pub struct SearchPanel {
+    pub query: SearchQuery,
+    pub replace: String,
+    pub focus: SearchField,
+    /// in structural mode, a match is the first token of a tree match
+    pub matches: Vec<Match>,
+    pub tree_matches: Vec<TreeMatch>,
+    pub current: usize,
+    pub error: Option<String>,
+    /// the tokens in any tree match, merged and sorted
+    covered: Vec<Range<usize>>,
+    /// the query and document version the matches are for
+    refreshed: Option<(SearchQuery, u64)>
+}
+
+impl SearchPanel {
+    pub fn new(text: String) -> SearchPanel {
+        SearchPanel {
+            query: SearchQuery { text, ..SearchQuery::default() },
+            replace: String::new(),
+            focus: SearchField::Query,
+            matches: vec![],
+            tree_matches: vec![],
+            current: 0,
+            error: None,
+            covered: vec![],
+            refreshed: None
+        }
+    }
+
+    /// the fields are single line, only typing and backspace are supported
+    pub fn edit(&mut self, edit_action: EditAction) {
+        let field = match self.focus {
+            SearchField::Query => &mut self.query.text,
+            SearchField::Replace => &mut self.replace
+        };
+        match edit_action {
+            EditAction::Insert(s) | EditAction::Paste(s) => field.extend(s.chars().filter(|c| *c != '\n')),
+            EditAction::Backspace => { field.pop(); },
+            _ => {}
+        }
+    }
+
+    pub fn toggle_focus(&mut self) {
+        self.focus = match self.focus {
+            SearchField::Query => SearchField::Replace,
+            SearchField::Replace => SearchField::Query
+        };
+    }
+
+    pub fn cycle_scope(&mut self, language: &Language) {
+        self.query.scope = (self.query.scope + 1) % language.search_scopes().len();
+    }
+
+    pub fn refresh(&mut self, version: u64, language: &Language, tokens: &Tokens, tree: &Tree) {
+        if self.refreshed.as_ref().map_or(false, |(q, v)| *q == self.query && *v == version) {
+            return;
+        }
+        self.refreshed = Some((self.query.clone(), version));
+        let res = if !self.query.structural {
+            find_matches(language, tokens, tree, &self.query).map(|matches| (matches, vec![]))
+        } else if self.query.text.trim().is_empty() {
+            Ok((vec![], vec![]))
+        } else {
+            find_tree_matches(language, tokens, tree, &self.query.text).map(|tree_matches| {
+                let matches = tree_matches.iter().map(|m| Match {
+                    token: m.range.start,
+                    range: 0..tokens[m.range.start].str.len()
+                }).collect();
+                (matches, tree_matches)
+            })
+        };
+        match res {
+            Ok((matches, tree_matches)) => {
+                self.matches = matches;
+                self.tree_matches = tree_matches;
+                self.error = None;
+            },
+            Err(e) => {
+                self.matches.clear();
+                self.tree_matches.clear();
+                self.error = Some(e);
+            }
+        }
+        if self.current >= self.matches.len() {
+            self.current = 0;
+        }
+        self.cover();
+    }
+
+    fn cover(&mut self) {
+        self.covered.clear();
+        let mut ranges: Vec<Range<usize>> = self.tree_matches.iter().map(|m| m.range.clone()).collect();
+        ranges.sort_by_key(|r| r.start);
+        for r in ranges {
+            match self.covered.last_mut() {
+                Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+                _ => self.covered.push(r)
+            }
+        }
+    }
+
+    /// the matches in a token and their indexes, the matches are sorted by token
+    pub fn matches_in(&self, token: usize) -> impl Iterator<Item=(usize, &Match)> {
+        let first = self.matches.binary_search_by(|m| if m.token < token { Ordering::Less } else { Ordering::Greater }).unwrap_err();
+        self.matches[first..].iter().take_while(move |m| m.token == token).enumerate().map(move |(i, m)| (first + i, m))
+    }
+
+    pub fn in_tree_match(&self, token: usize) -> bool {
+        let next = self.covered.binary_search_by(|r| if r.start <= token { Ordering::Less } else { Ordering::Greater }).unwrap_err();
+        next > 0 && self.covered[next - 1].contains(&token)
+    }
+
+    /// the first match after the selection, or before it going backward, wrapping around
+    pub fn step(&mut self, token: usize, selection: Range<usize>, forward: bool) -> Option<&Match> {
+        let len = self.matches.len();
+        if len == 0 {
+            return None;
+        }
+        self.current = if forward {
+            self.matches.iter().position(|m| (m.token, m.range.start) >= (token, selection.end)).unwrap_or(0)
+        } else {
+            self.matches.iter().rposition(|m| (m.token, m.range.start) < (token, selection.start)).unwrap_or(len - 1)
+        };
+        self.matches.get(self.current)
+    }
+
+    pub fn status(&self, language: &Language) -> String {
+        let scope = language.search_scopes().get(self.query.scope).map_or("all", |s| s.name);
+        let mode = if self.query.structural { "tree" } else if self.query.regex { "regex" } else { "text" };
+        match &self.error {
+            Some(e) => format!("{} · {} · {}", mode, scope, e.lines().last().unwrap_or("")),
+            None if self.matches.is_empty() => format!("{} · {} · no results", mode, scope),
+            None => format!("{} · {} · {}/{}", mode, scope, self.current + 1, self.matches.len())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use druid::im::vector;
+    use crate::languages::json::INSTANCE as JSON;
+
+    fn state() -> EditorState {
+        EditorState::with_tokens(&JSON, vector![
+            Token::new(5, "["),
+            Token::new(7, "ab"),
+            Token::new(2, ","),
+            Token::new(7, "cab"),
+            Token::new(2, ","),
+            Token::new(7, "aa"),
+            Token::new(6, "]")
+        ])
+    }
+
+    #[test]
+    fn matches_in_token() {
+        let data = state();
+        let mut search = SearchPanel::new(String::from("a"));
+        search.refresh(data.version, data.language, &data.tokens, &data.tree);
+        let found = |t: usize| search.matches_in(t).map(|(i, m)| (i, m.range.clone())).collect::<Vec<_>>();
+        assert_eq!(found(0), vec![]);
+        assert_eq!(found(1), vec![(0, 0..1)]);
+        assert_eq!(found(3), vec![(1, 1..2)]);
+        assert_eq!(found(5), vec![(2, 0..1), (3, 1..2)]);
+        assert_eq!(found(6), vec![]);
+    }
+
+    #[test]
+    fn refresh_when_changed() {
+        let data = state();
+        let mut search = SearchPanel::new(String::from("a"));
+        search.refresh(data.version, data.language, &data.tokens, &data.tree);
+        search.matches.clear();
+        search.refresh(data.version, data.language, &data.tokens, &data.tree);
+        assert!(search.matches.is_empty());
+        search.refresh(data.version + 1, data.language, &data.tokens, &data.tree);
+        assert_eq!(search.matches.len(), 4);
+        search.query.text = String::from("aa");
+        search.refresh(data.version + 1, data.language, &data.tokens, &data.tree);
+        assert_eq!(search.matches.len(), 1);
+    }
+
+    #[test]
+    fn keys_scope() {
+        let data = EditorState::with_tokens(&JSON, vector![
+            Token::new(1, "{"),
+            Token::new(7, "a"),
+            Token::new(4, ":"),
+            Token::new(7, "a"),
+            Token::new(3, "}")
+        ]);
+        let query = SearchQuery { text: String::from("a"), scope: 1, ..SearchQuery::default() };
+        let matches = find_matches(data.language, &data.tokens, &data.tree, &query).unwrap();
+        assert_eq!(matches.iter().map(|m| m.token).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(JSON.category(7, || token_node(&data.tree, 1)), "string.key");
+        assert_eq!(JSON.category(7, || token_node(&data.tree, 3)), category::STRING);
+    }
+
+    #[test]
+    fn in_tree_match() {
+        let mut search = SearchPanel::new(String::new());
+        search.tree_matches = vec![4..6, 1..3, 2..3, 5..8].into_iter().map(|range| TreeMatch { range, captures: HashMap::new() }).collect();
+        search.cover();
+        let covered: Vec<usize> = (0..10).filter(|&t| search.in_tree_match(t)).collect();
+        assert_eq!(covered, vec![1, 2, 4, 5, 6, 7]);
+    }
+}
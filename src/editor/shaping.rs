@@ -0,0 +1,148 @@
+use unicode_bidi::{bidi_class, BidiClass, BidiInfo};
+
+use crate::*;
+
+/// the text shown for a fragment of a token
+pub struct Shaped {
+    pub text: String,
+    /// char boundaries of the fragment and where they are in `text`, empty when they are the same
+    pub map: Vec<(usize, usize)>,
+    /// where the pre-edit text is in `text`
+    pub preedit: Option<(usize, usize)>
+}
+
+fn is_invisible(c: char) -> bool {
+    c.is_control() || match c {
+        '\u{00AD}' | '\u{061C}' | '\u{180E}' | '\u{FEFF}' => true,
+        '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2060}'..='\u{2064}' | '\u{2066}'..='\u{206F}' => true,
+        _ => false
+    }
+}
+
+fn is_rtl_char(c: char) -> bool {
+    match bidi_class(c) {
+        BidiClass::R | BidiClass::AL | BidiClass::RLE | BidiClass::RLO | BidiClass::RLI => true,
+        _ => false
+    }
+}
+
+/// `preedit` is inserted at an offset of the fragment, the caret is after it
+pub fn shape(str: &str, shaping: Shaping, preedit: Option<(usize, &str)>) -> Shaped {
+    let escape = shaping.show_codepoints && str.chars().any(is_invisible);
+    // the override is only needed when something would be reordered
+    let reorder = !shaping.bidi && str.chars().any(is_rtl_char);
+    if !escape && !reorder && preedit.is_none() {
+        return Shaped { text: String::from(str), map: vec![], preedit: None };
+    }
+    let mut res = Shaped { text: String::new(), map: vec![], preedit: None };
+    if reorder {
+        // left-to-right override, popped at the end
+        res.text.push('\u{202D}');
+    }
+    let boundary = |i: usize, res: &mut Shaped| {
+        match preedit {
+            Some((at, p)) if at == i => {
+                res.preedit = Some((res.text.len(), res.text.len() + p.len()));
+                res.text.push_str(p);
+            },
+            _ => {}
+        }
+        res.map.push((i, res.text.len()));
+    };
+    for (i, c) in str.char_indices() {
+        boundary(i, &mut res);
+        if escape && is_invisible(c) {
+            res.text.push_str(&format!("\\u{{{:X}}}", c as u32));
+        } else {
+            res.text.push(c);
+        }
+    }
+    boundary(str.len(), &mut res);
+    if reorder {
+        res.text.push('\u{202C}');
+    }
+    res
+}
+
+/// where a char boundary of the fragment is in `Shaped::text`, see `Shaped::map`
+pub fn shaped_position(map: &[(usize, usize)], offset: usize) -> usize {
+    match map.binary_search_by_key(&offset, |m| m.0) {
+        Ok(i) => map[i].1,
+        Err(i) if i > 0 => map[i - 1].1,
+        Err(_) => offset
+    }
+}
+
+/// the last char boundary of the fragment at or before a position in `Shaped::text`
+pub fn text_offset(map: &[(usize, usize)], pos: usize) -> usize {
+    match map.iter().rev().find(|m| m.1 <= pos).or(map.first()) {
+        Some(m) => m.0,
+        None => pos
+    }
+}
+
+/// a token with bidi shaping whose text starts right to left. the whole token is treated as one run, so movement in
+/// mixed text is only approximately visual
+pub fn is_rtl(language: &Language, token: &Token) -> bool {
+    language.node(token.tp).as_token().shaping().bidi && !token.str.is_empty()
+        && BidiInfo::new(&token.str.slice(0..token.str.grapheme_boundary(256)), None).paragraphs.first().map_or(false, |p| p.level.is_rtl())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ESCAPED: Shaping = Shaping { bidi: true, show_codepoints: true };
+
+    #[test]
+    fn plain_text() {
+        let shaped = shape("ab", ESCAPED, None);
+        assert_eq!((shaped.text.as_str(), shaped.map.len()), ("ab", 0));
+        assert_eq!((shaped_position(&shaped.map, 1), text_offset(&shaped.map, 1)), (1, 1));
+    }
+
+    #[test]
+    fn escaped_invisible() {
+        let shaped = shape("a\u{200B}b", ESCAPED, None);
+        assert_eq!(shaped.text, "a\\u{200B}b");
+        assert_eq!(shaped.map, vec![(0, 0), (1, 1), (4, 9), (5, 10)]);
+        assert_eq!(shaped_position(&shaped.map, 4), 9);
+        // inside the escape is the invisible char
+        assert_eq!(text_offset(&shaped.map, 5), 1);
+        assert_eq!(text_offset(&shaped.map, 9), 4);
+        assert_eq!(text_offset(&shaped.map, 10), 5);
+        // not escaped without `show_codepoints`
+        assert_eq!(shape("a\u{200B}b", Shaping::default(), None).text, "a\u{200B}b");
+    }
+
+    #[test]
+    fn rtl_without_bidi() {
+        let shaped = shape("אב", Shaping::default(), None);
+        assert_eq!(shaped.text, "\u{202D}אב\u{202C}");
+        assert_eq!(shaped.map, vec![(0, 3), (2, 5), (4, 7)]);
+        assert_eq!(shaped_position(&shaped.map, 2), 5);
+        // the override char is before the first boundary
+        assert_eq!(text_offset(&shaped.map, 0), 0);
+        assert_eq!(text_offset(&shaped.map, 5), 2);
+        assert_eq!(text_offset(&shaped.map, 10), 4);
+        // reordered as usual with bidi
+        assert_eq!(shape("אב", ESCAPED, None).text, "אב");
+    }
+
+    #[test]
+    fn preedit_inserted() {
+        let shaped = shape("ab", Shaping::default(), Some((1, "xy")));
+        assert_eq!(shaped.text, "axyb");
+        assert_eq!(shaped.preedit, Some((1, 3)));
+        assert_eq!(shaped.map, vec![(0, 0), (1, 3), (2, 4)]);
+        // the caret is after the pre-edit text
+        assert_eq!(shaped_position(&shaped.map, 1), 3);
+        // inside the pre-edit text is before it
+        assert_eq!(text_offset(&shaped.map, 2), 0);
+        assert_eq!(text_offset(&shaped.map, 3), 1);
+        assert_eq!(text_offset(&shaped.map, 4), 2);
+        let shaped = shape("ab", Shaping::default(), Some((2, "x")));
+        assert_eq!((shaped.text.as_str(), shaped.preedit), ("abx", Some((2, 3))));
+        assert_eq!(shaped_position(&shaped.map, 2), 3);
+    }
+}
@@ -0,0 +1,1175 @@
+use std::ops::Range;
+use std::cmp::Ordering;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use druid::text::{Selection, EditAction, Movement};
+use tree_sitter::{Parser, Node, Tree, InputEdit};
+use druid::im::vector;
+
+use crate::*;
+use crate::editor::*;
+
+#[derive(Clone, Debug)]
+pub enum Cursor {
+    Point {
+        token: usize,
+        selection: Selection
+    }
+}
+
+impl PartialEq for Cursor {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Cursor::Point {token: t1, selection: s1}, Cursor::Point { token: t2, selection: s2}) =>
+                t1 == t2 && s1.start == s2.start && s1.end == s2.end,
+            _ => false
+        }
+    }
+}
+
+impl Eq for Cursor {}
+
+
+pub struct EditorState {
+    pub version: u64,
+    pub language: &'static Language,
+    pub tokens: Tokens,
+    /// the primary cursor, edits are done here, the others take turns to be the primary cursor
+    pub cursor: Cursor,
+    pub cursors: Vec<Cursor>,
+    parser: Parser,
+    /// the token types tree-sitter parses, edited along the tokens
+    kinds: Kinds,
+    pub tree: Tree,
+    pub diagnostics: Vec<Diagnostic>,
+    pub severities: Severities,
+    /// holes and lex errors, kept along the tokens on each edit, see `edit_token_diagnostics`
+    token_diagnostics: Vec<Diagnostic>,
+    /// the version `diagnostics` are computed for, they are only computed once an edit is finished
+    diagnosed: u64,
+    /// folded tree nodes and where their first token was last seen, which follows the edits like the cursors do, see
+    /// `folded`
+    folds: BTreeMap<NodeId, usize>,
+    /// see `token_index`
+    ids: RefCell<TokenIndex>,
+    /// IME pre-edit text
+    composition: Option<String>,
+    /// snapshots before each edit, tokens are persistent so they are cheap
+    undo: Vec<(Tokens, Cursor)>,
+    redo: Vec<(Tokens, Cursor)>
+}
+
+/// the oldest undo steps are dropped
+const UNDO_LIMIT: usize = 1000;
+
+const tree_sitter_point_zero: tree_sitter::Point = tree_sitter::Point { row: 0, column: 0 };
+const tree_sitter_point_one: tree_sitter::Point = tree_sitter::Point { row: 0, column: 1 };
+
+fn missing_node(node: Node) -> Option<(usize, u16)> {
+    if node.is_missing() {
+        return Some((node.start_byte(), node.kind_id()));
+    }
+    if node.has_error() {
+        let mut cursor = node.walk();
+        let mut has_child = cursor.goto_first_child();
+        while has_child {
+            if let Some(res) = missing_node(cursor.node()) {
+                return Some(res);
+            }
+            has_child = cursor.goto_next_sibling();
+        }
+    }
+    None
+}
+
+impl EditorState {
+    pub fn new() -> EditorState {
+        let tokens = vector![
+            Token::new(1, "{"),
+            Token::new(7, "key2"),
+            Token::new(4, ":"),
+            Token::new(7, "valuevaluevalueأَلْحُرُوف ٱلْعَرَبِيَّة😄😁😆 value valul"),
+            Token::new(2, ","),
+            Token::new(7, "key"),
+            Token::new(4, ":"),
+            Token::new(5, "["),
+            Token::new(7, "key"),
+            Token::new(2, ","),
+            Token::new(7, "key"),
+            Token::new(2, ","),
+            Token::new(7, "key"),
+            Token::new(2, ","),
+            Token::new(7, "key"),
+            Token::new(2, ","),
+            Token::new(7, "key"),
+            Token::new(2, ","),
+            Token::new(7, "key"),
+            Token::new(2, ","),
+            Token::new(7, "key"),
+            Token::new(2, ","),
+            Token::new(7, "key"),
+            Token::new(2, ","),
+            Token::new(7, "key"),
+            Token::new(2, ","),
+            Token::new(7, "key"),
+            Token::new(2, ","),
+            Token::new(7, "key"),
+            Token::new(6, "]"),
+            Token::new(2, ","),
+            Token::new(7, "key"),
+            Token::new(4, ":"),
+            Token::new(8, "1000"),
+            Token::new(2, ","),
+            Token::new(7, "key3"),
+            Token::new(4, ":"),
+            Token::new(11, "true"),
+            Token::new(3, "}")
+        ];
+        EditorState::with_tokens(&crate::languages::json::INSTANCE, tokens)
+    }
+
+    pub fn with_tokens(language: &'static Language, tokens: Tokens) -> EditorState {
+        let cursor = Cursor::Point { token: 0, selection: Selection { start : 0, end : 0 } };
+
+        let mut parser = Parser::new();
+        parser.set_language(language.language()).unwrap();
+        let kinds = Kinds::new(&tokens);
+        let tree = parser.parse_with(&mut |byte, _| kinds.chunk_at(byte), None).unwrap();
+        let token_diagnostics = token_diagnostics(language, &tokens, 0..tokens.len());
+        let mut state = EditorState {
+            version: 0,
+            language,
+            tokens, cursor, cursors: vec![], tree, parser, kinds,
+            diagnostics: vec![],
+            severities: Severities::new(&[]),
+            token_diagnostics,
+            diagnosed: u64::MAX,
+            folds: BTreeMap::new(),
+            ids: RefCell::new(TokenIndex::new()),
+            composition: None,
+            undo: vec![],
+            redo: vec![]
+        };
+        state.fill_holes();
+        state
+    }
+
+    /// tokens `start..end` are replaced by `start..new_end`, the other cursors follow
+    fn shift_cursors(&mut self, start: usize, end: usize, new_end: usize) {
+        let len = self.tokens.len();
+        for c in &mut self.cursors {
+            match c {
+                Cursor::Point { token, selection } => {
+                    if *token >= end {
+                        *token = *token + new_end - end;
+                    } else if *token >= new_end {
+                        // the token is removed
+                        *token = start.min(len.saturating_sub(1));
+                        *selection = Selection::caret(0);
+                    }
+                }
+            }
+        }
+    }
+
+    /// text `range` of `token` is replaced by `len` bytes, the other cursors in the token follow
+    fn shift_cursors_in(&mut self, token: usize, range: Range<usize>, len: usize) {
+        let shift = |offset: usize| if offset >= range.end {
+            offset - range.end + range.start + len
+        } else if offset > range.start {
+            range.start + len
+        } else {
+            offset
+        };
+        for c in &mut self.cursors {
+            match c {
+                Cursor::Point { token: t, selection } => if *t == token {
+                    *selection = Selection::new(shift(selection.start), shift(selection.end));
+                }
+            }
+        }
+    }
+
+    /// tokens `start..end` are replaced by `start..new_end`, the fold hints follow, a hint of a removed token is checked
+    /// and dropped in `retain_folds`
+    fn shift_folds(&mut self, start: usize, end: usize, new_end: usize) {
+        for first in self.folds.values_mut() {
+            if *first >= end {
+                *first = *first - end + new_end;
+            } else if *first >= new_end {
+                *first = start;
+            }
+        }
+    }
+
+    /// the hints are found again by id, when tokens are changed all over the place
+    fn relocate_folds(&mut self) {
+        if self.folds.is_empty() {
+            return;
+        }
+        let folds = self.folds.keys().filter_map(|&f| self.token_index(f.first).map(|i| (f, i))).collect();
+        self.folds = folds;
+    }
+
+    /// the index of a token, `None` once it is removed
+    pub fn token_index(&self, id: TokenId) -> Option<usize> {
+        self.ids.borrow_mut().get(&self.tokens, id)
+    }
+
+    /// the tokens of a node in the current tree, see `node`
+    pub fn node_range(&self, id: NodeId) -> Option<Range<usize>> {
+        let first = self.token_index(id.first)?;
+        self.node(id, first).map(|n| n.start_byte()..n.end_byte())
+    }
+
+    /// `None` for a missing node, which has no tokens
+    pub fn node_id(&self, node: Node) -> Option<NodeId> {
+        if node.end_byte() <= node.start_byte() {
+            return None;
+        }
+        Some(NodeId { kind: node.kind_id(), first: self.tokens[node.start_byte()].id, last: self.tokens[node.end_byte() - 1].id })
+    }
+
+    /// the node in the current tree when its first token is still at `first`, the innermost one when nodes of the same
+    /// type have the same tokens
+    pub fn node(&self, id: NodeId, first: usize) -> Option<Node> {
+        if self.tokens.get(first).map_or(true, |t| t.id != id.first) {
+            return None;
+        }
+        let mut node = self.tree.root_node().descendant_for_byte_range(first, first + 1);
+        while let Some(n) = node {
+            if n.start_byte() != first {
+                return None;
+            }
+            if n.kind_id() == id.kind && n.end_byte() > first && self.tokens[n.end_byte() - 1].id == id.last {
+                return Some(n);
+            }
+            node = n.parent();
+        }
+        None
+    }
+
+    /// the tree node whose start delimiter is token `start`
+    fn fold_id(&self, start: usize) -> Option<NodeId> {
+        tree_node_at(self.language, &self.tree, start).and_then(|n| self.node_id(n))
+    }
+
+    /// start delimiters of folded tree nodes
+    pub fn folded(&self) -> BTreeSet<usize> {
+        self.folds.iter().filter_map(|(&f, &first)| self.node(f, first)).map(|n| n.start_byte()).collect()
+    }
+
+    pub fn is_folded(&self, start: usize) -> bool {
+        self.fold_id(start).map_or(false, |f| self.folds.contains_key(&f))
+    }
+
+    /// keep the folds whose node still exists, and can still be folded
+    fn retain_folds(&mut self) {
+        let folds = self.folds.iter().map(|(&f, &first)| (f, first)).filter(|&(f, first)| match self.node(f, first) {
+            Some(n) => tree_node_at(self.language, &self.tree, n.start_byte()) == Some(n),
+            None => false
+        }).collect();
+        self.folds = folds;
+    }
+
+    pub fn toggle_fold(&mut self, start: usize) {
+        if let Some(f) = self.fold_id(start) {
+            if self.folds.remove(&f).is_none() {
+                self.folds.insert(f, start);
+                self.reveal_fold(start);
+            }
+        }
+    }
+
+    /// fold the innermost unfolded tree node around the cursor
+    pub fn fold_at_cursor(&mut self) {
+        let (token, _) = self.cursor_point();
+        let fold = enclosing_trees(self.language, &self.tree, token).into_iter()
+            .filter_map(|n| self.node_id(n).map(|f| (n.start_byte(), f)))
+            .find(|(_, f)| !self.folds.contains_key(f));
+        if let Some((start, f)) = fold {
+            self.folds.insert(f, start);
+            self.reveal_fold(start);
+        }
+    }
+
+    /// unfold the innermost folded tree node around the cursor
+    pub fn unfold_at_cursor(&mut self) {
+        let (token, _) = self.cursor_point();
+        let fold = enclosing_trees(self.language, &self.tree, token).into_iter()
+            .filter_map(|n| self.node_id(n))
+            .find(|f| self.folds.contains_key(f));
+        if let Some(f) = fold {
+            self.folds.remove(&f);
+        }
+    }
+
+    pub fn fold_all(&mut self) {
+        let mut starts = vec![];
+        tree_nodes(self.language, self.tree.root_node(), &mut starts);
+        // the outermost one is kept open
+        let starts: Vec<usize> = starts.into_iter().skip(1).collect();
+        self.folds = starts.iter().filter_map(|&s| self.fold_id(s).map(|f| (f, s))).collect();
+        for s in starts {
+            self.reveal_fold(s);
+        }
+    }
+
+    pub fn unfold_all(&mut self) {
+        self.folds.clear();
+    }
+
+    /// cursors hidden by a new fold move to its start delimiter
+    fn reveal_fold(&mut self, start: usize) {
+        if let Some(range) = fold_range(self.language, &self.tree, start) {
+            let len = self.tokens[start].str.len();
+            for c in std::iter::once(&mut self.cursor).chain(self.cursors.iter_mut()) {
+                match c {
+                    Cursor::Point { token, selection } => if range.contains(token) {
+                        *token = start;
+                        *selection = Selection::caret(len);
+                    }
+                }
+            }
+        }
+    }
+
+    /// folds with a cursor inside are opened, after the cursor moves there
+    fn reveal_cursors(&mut self) {
+        let (language, tree) = (self.language, &self.tree);
+        let tokens: Vec<usize> = std::iter::once(&self.cursor).chain(self.cursors.iter()).map(|c| match c {
+            Cursor::Point { token, .. } => *token
+        }).collect();
+        let folds = self.folds.iter().map(|(&f, &first)| (f, first)).filter(|&(f, first)| {
+            self.node(f, first).and_then(|n| fold_range(language, tree, n.start_byte())).map_or(false, |r| !tokens.iter().any(|t| r.contains(t)))
+        }).collect();
+        self.folds = folds;
+    }
+
+    fn reparse(&mut self, start: usize, end: usize, new_end: usize) {
+        self.version += 1;
+        self.shift_cursors(start, end, new_end);
+        self.shift_folds(start, end, new_end);
+        self.ids.get_mut().edit(start, end, new_end);
+        self.tree.edit(&InputEdit {
+            start_byte: start,
+            old_end_byte: end,
+            new_end_byte: new_end,
+            start_position: tree_sitter_point_zero,
+            old_end_position: tree_sitter_point_one,
+            new_end_position: tree_sitter_point_one
+        });
+        self.kinds.edit(start, end, new_end, &self.tokens);
+        self.edit_token_diagnostics(start, end, new_end);
+        let kinds = &self.kinds;
+        self.tree = self.parser.parse_with(&mut |byte, _| kinds.chunk_at(byte), Some(&self.tree)).unwrap();
+        self.retain_folds();
+        if self.tokens.is_empty() {
+            self.tokens.push_back(Token::new(self.language.lex_error(), ""));
+            self.reparse(0, 0, 1)
+        } else if self.language.node(self.tokens[0].tp).as_token().is_separator() {
+            self.tokens.push_front(Token::new(self.language.lex_error(), ""));
+            self.reparse(0, 0, 1)
+        } else if self.language.node(self.tokens.last().unwrap().tp).as_token().is_separator() {
+            let len = self.tokens.len();
+            self.tokens.push_back(Token::new(self.language.lex_error(), ""));
+            self.reparse(len, len, len + 1);
+        }
+    }
+
+    /// parse from scratch, when tokens are changed all over the place
+    pub fn reparse_all(&mut self) {
+        self.version += 1;
+        self.kinds = Kinds::new(&self.tokens);
+        self.ids.get_mut().reset();
+        self.token_diagnostics = token_diagnostics(self.language, &self.tokens, 0..self.tokens.len());
+        let kinds = &self.kinds;
+        self.tree = self.parser.parse_with(&mut |byte, _| kinds.chunk_at(byte), None).unwrap();
+        self.relocate_folds();
+        self.retain_folds();
+        let tokens = &self.tokens;
+        for c in std::iter::once(&mut self.cursor).chain(self.cursors.iter_mut()) {
+            match c {
+                Cursor::Point { token, selection } => {
+                    *token = (*token).min(tokens.len() - 1);
+                    *selection = tokens[*token].str.constrain(*selection);
+                }
+            }
+        }
+        self.fill_holes();
+    }
+
+    /// remember the tokens before an edit
+    fn checkpoint(&mut self) {
+        self.undo.push((self.tokens.clone(), self.cursor.clone()));
+        if self.undo.len() > UNDO_LIMIT {
+            self.undo.remove(0);
+        }
+        self.redo.clear();
+    }
+
+    pub fn undo(&mut self) {
+        if let Some((tokens, cursor)) = self.undo.pop() {
+            self.redo.push((std::mem::replace(&mut self.tokens, tokens), std::mem::replace(&mut self.cursor, cursor)));
+            self.cursors.clear();
+            self.reparse_all();
+        }
+    }
+
+    pub fn redo(&mut self) {
+        if let Some((tokens, cursor)) = self.redo.pop() {
+            self.undo.push((std::mem::replace(&mut self.tokens, tokens), std::mem::replace(&mut self.cursor, cursor)));
+            self.cursors.clear();
+            self.reparse_all();
+        }
+    }
+
+    /// the token type is kept when it still accepts the text
+    fn relex(&mut self, t: usize) {
+        let token = &mut self.tokens[t];
+        token.hole = false;
+        if !self.language.node(token.tp).as_token().accept(&token.str.as_str()) {
+            token.tp = self.language.try_lex(&token.str.as_str()).unwrap_or(self.language.lex_error());
+        }
+    }
+
+    /// replace the matches as a single undo step, the changed tokens are lexed again
+    pub fn replace(&mut self, matches: &[Match], query: &SearchQuery, replacement: &str) -> Result<(), String> {
+        let re = query.matcher()?;
+        if matches.is_empty() {
+            return Ok(());
+        }
+        self.checkpoint();
+        let mut starts: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for m in matches {
+            starts.entry(m.token).or_default().push(m.range.start);
+        }
+        for (token, starts) in starts {
+            let text = replace_matches(&re, query.regex, &self.tokens[token].str.as_str(), replacement, &starts);
+            self.tokens[token].str = TokenText::from(text);
+            self.relex(token);
+        }
+        self.reparse_all();
+        Ok(())
+    }
+
+    /// replace the tokens of tree matches by the template as a single undo step
+    pub fn replace_tree(&mut self, matches: &[TreeMatch], template: &str) {
+        if matches.is_empty() {
+            return;
+        }
+        self.checkpoint();
+        // from the back so the ranges of the earlier matches are still valid
+        for m in matches.iter().rev() {
+            let new = expand_template(self.language, &self.tokens, m, template);
+            let tail = self.tokens.split_off(m.range.end);
+            self.tokens.truncate(m.range.start);
+            self.tokens.append(new);
+            self.tokens.append(tail);
+        }
+        if self.tokens.is_empty() {
+            self.tokens.push_back(Token::new(self.language.lex_error(), ""));
+        }
+        self.reparse_all();
+    }
+
+    /// select text of a token, like a search match
+    pub fn select(&mut self, token: usize, range: Range<usize>) {
+        self.cursor = Cursor::Point { token, selection: Selection::new(range.start, range.end) };
+        self.reveal_cursors();
+    }
+
+    /// fill missing nodes with holes, this is done after an edit is finished, so the edit itself can insert the
+    /// missing tokens first. the diagnostics are computed last
+    fn fill_holes(&mut self) {
+        while let Some((index, tp)) = missing_node(self.tree.root_node()) {
+            if self.tokens.get(index).map_or(false, |t| t.hole && t.tp == tp) {
+                break;
+            }
+            self.tokens.insert(index, Token::hole(tp));
+            match &mut self.cursor {
+                Cursor::Point { token, .. } => if *token >= index {
+                    *token += 1;
+                }
+            }
+            self.reparse(index, index, index + 1);
+        }
+        self.update_diagnostics();
+    }
+
+    /// tokens `start..end` are replaced by `start..new_end`, the token after them is checked again too, as a lex error
+    /// expects what can follow the token before it
+    fn edit_token_diagnostics(&mut self, start: usize, end: usize, new_end: usize) {
+        let ds = &mut self.token_diagnostics;
+        let from = ds.binary_search_by(|d| if d.range.start < start { Ordering::Less } else { Ordering::Greater }).unwrap_err();
+        let to = ds.binary_search_by(|d| if d.range.start <= end { Ordering::Less } else { Ordering::Greater }).unwrap_err();
+        for d in &mut ds[to..] {
+            d.range = d.range.start - end + new_end..d.range.end - end + new_end;
+        }
+        let new = token_diagnostics(self.language, &self.tokens, start..(new_end + 1).min(self.tokens.len()));
+        self.token_diagnostics.splice(from..to, new);
+    }
+
+    /// the tree is only walked where it has errors
+    fn update_diagnostics(&mut self) {
+        if self.diagnosed != self.version {
+            self.diagnostics = merge(&self.token_diagnostics, syntax_diagnostics(self.language, &self.tokens, &self.tree));
+            self.severities = Severities::new(&self.diagnostics);
+            self.diagnosed = self.version;
+        }
+    }
+
+    /// the token can be completed when it is empty or not lexed
+    pub fn completion(&self) -> Option<Completion> {
+        let (token, _) = self.cursor_point();
+        let t = &self.tokens[token];
+        if t.str.is_empty() || t.tp == self.language.lex_error() {
+            Completion::new(self.language, &self.tokens, token)
+        } else {
+            None
+        }
+    }
+
+    /// set the type and text of token directly, nothing happens when the token is gone or the type isn't a token
+    pub fn complete(&mut self, token: usize, tp: u16) {
+        let is_token = match self.language.node(tp) {
+            NodeSpec::Token(_) => true,
+            _ => false
+        };
+        if token >= self.tokens.len() || !is_token {
+            return;
+        }
+        self.checkpoint();
+        let t = &mut self.tokens[token];
+        t.hole = false;
+        t.tp = tp;
+        match self.language.node(tp) {
+            NodeSpec::Token(TokenSpec::Constant { str, .. }) => t.str = TokenText::from(*str),
+            // picked by name, see `Completion::new`
+            NodeSpec::Token(spec) => if !spec.accept(&t.str.as_str()) {
+                t.str = TokenText::from("");
+            },
+            _ => {}
+        }
+        let len = t.str.len();
+        self.cursor = Cursor::Point { token, selection: Selection::caret(len) };
+        self.reparse(token, token + 1, token + 1);
+        self.fill_holes();
+    }
+
+    /// F8 jumps to the next diagnostic
+    pub fn jump_to_diagnostic(&mut self, forward: bool) {
+        let (token, _) = self.cursor_point();
+        let target = if forward {
+            self.diagnostics.iter().find(|d| d.range.start > token).or(self.diagnostics.first())
+        } else {
+            self.diagnostics.iter().rev().find(|d| d.range.start < token).or(self.diagnostics.last())
+        };
+        if let Some(d) = target {
+            let index = d.range.start.min(self.tokens.len() - 1);
+            self.set_cursor(index, 0);
+        }
+    }
+
+    /// Tab jumps to the next hole
+    pub fn jump_to_hole(&mut self, forward: bool) {
+        let (token, _) = self.cursor_point();
+        let len = self.tokens.len();
+        for i in 1..=len {
+            let index = if forward { (token + i) % len } else { (token + len - i) % len };
+            if self.tokens[index].hole {
+                self.cursor = Cursor::Point { token: index, selection: Selection::caret(0) };
+                return;
+            }
+        }
+    }
+
+    /// `edited` is the changed range of the text
+    fn lex_sync_then_sit(&mut self, t: usize, edited: Range<usize>) {
+        // the text is changed even when the types are not, so search and diagnostics are refreshed
+        self.version += 1;
+        self.edit_token_diagnostics(t, t + 1, t + 1);
+        let token = &mut self.tokens[t];
+        let spec = self.language.node(token.tp).as_token();
+        if token.hole {
+            // a hole is always empty
+        } else if token.str.is_empty() {
+            if !spec.can_empty() {
+                self.move_selection(Movement::Left, false);
+                self.tokens.remove(t);
+                self.reparse(t, t + 1, t);
+            }
+        } else {
+            // a large token is only lexed around the edit, kinds which can get that large, like strings, are expected
+            // to accept any part of their text
+            let tp = {
+                let (_, text) = token.str.window(edited);
+                if spec.is_lex_error() {
+                    self.language.try_lex(&text)
+                } else if !spec.accept(&text) {
+                    Some(self.language.lex_error())
+                } else {
+                    None
+                }
+            };
+            if let Some(tp) = tp {
+                token.tp = tp;
+                self.reparse(t, t + 1, t + 1);
+            }
+        }
+    }
+
+    fn insert(&mut self, new: &str) {
+        if !self.step_over(new) {
+            self.insert_text(new);
+            self.auto_close();
+        }
+    }
+
+    /// typing the end delimiter right before an existing one just moves over it, unless the current token takes it,
+    /// like a string does
+    fn step_over(&mut self, new: &str) -> bool {
+        let (token, offset) = self.cursor_point();
+        let current = &self.tokens[token];
+        let is_slot = current.str.is_empty() && !current.hole;
+        if offset != current.str.len() || token + 1 >= self.tokens.len() {
+            return false;
+        }
+        if !is_slot && !current.hole {
+            let (_, text) = current.str.window(offset..offset);
+            if self.language.node(current.tp).as_token().accept(&(text.into_owned() + new)) {
+                return false;
+            }
+        }
+        let next = &self.tokens[token + 1];
+        let (tp, hole) = (next.tp, next.hole);
+        match self.language.node(tp) {
+            NodeSpec::Token(TokenSpec::Constant { str, .. }) if *str == new && self.language.is_end_delimiter(tp) => {
+                let mut index = token + 1;
+                if hole {
+                    // the hole is filled in place, its id is kept
+                    let t = &mut self.tokens[index];
+                    t.hole = false;
+                    t.str = TokenText::from(*str);
+                    self.reparse(index, index + 1, index + 1);
+                }
+                if is_slot {
+                    self.tokens.remove(token);
+                    self.reparse(token, token + 1, token);
+                    index -= 1;
+                }
+                self.cursor = Cursor::Point { token: index, selection: Selection::caret(new.len()) };
+                true
+            },
+            _ => false
+        }
+    }
+
+    /// after a start delimiter is typed, the end delimiter of the same tree is inserted, with an empty slot between
+    fn auto_close(&mut self) {
+        let (token, offset) = self.cursor_point();
+        let current = &self.tokens[token];
+        if current.hole || offset != current.str.len() {
+            return;
+        }
+        if let Some(end) = self.language.closing(current.tp) {
+            if let NodeSpec::Token(TokenSpec::Constant { str, .. }) = self.language.node(end) {
+                self.tokens.insert(token + 1, Token::new(self.language.lex_error(), ""));
+                self.tokens.insert(token + 2, Token::new(end, *str));
+                self.cursor = Cursor::Point { token: token + 1, selection: Selection::caret(0) };
+                self.reparse(token + 1, token + 1, token + 3);
+            }
+        }
+    }
+
+    fn insert_text(&mut self, new: &str) {
+        match &mut self.cursor {
+            Cursor::Point { token, selection } => {
+                let token = *token;
+                let t = &mut self.tokens[token];
+                if t.hole {
+                    // typing into a hole replaces it with a real token
+                    t.hole = false;
+                    t.str = TokenText::from(new);
+                    t.tp = self.language.try_lex(new).unwrap_or(self.language.lex_error());
+                    *selection = Selection::caret(new.len());
+                    self.shift_cursors_in(token, 0..0, new.len());
+                    self.reparse(token, token + 1, token + 1);
+                    return;
+                }
+                let text = &mut t.str;
+                *selection = text.constrain(*selection);
+                let range = selection.range();
+                text.edit(range.clone(), new);
+                *selection = Selection::caret(selection.min() + new.len());
+                self.shift_cursors_in(token, range.clone(), new.len());
+                self.lex_sync_then_sit(token, range.start..range.start + new.len());
+            },
+        }
+    }
+
+    fn delete_backward(&mut self) {
+        match &mut self.cursor {
+            Cursor::Point { token, selection } => {
+                let token = *token;
+                let text = &mut self.tokens[token].str;
+                if text.is_empty() {
+                    self.move_selection(Movement::Left, false);
+                    self.tokens.remove(token);
+                    self.reparse(token, token + 1, token);
+                } else {
+                    let range = if selection.is_caret() {
+                        text.offset_for_delete_backwards(selection.end)..selection.end
+                    } else {
+                        selection.range()
+                    };
+                    text.edit(range.clone(), "");
+                    *selection = Selection::caret(range.start);
+                    self.shift_cursors_in(token, range.clone(), 0);
+                    self.lex_sync_then_sit(token, range.start..range.start);
+                }
+            },
+        }
+    }
+
+    fn delete_forward(&mut self) {
+        match &mut self.cursor {
+            Cursor::Point { token, selection } => {
+                let token = *token;
+                let text = &mut self.tokens[token].str;
+                if text.is_empty() {
+
+                    self.tokens.remove(token);
+                    self.reparse(token, token + 1, token);
+                } else {
+                    if selection.is_caret() {
+                        // Never touch the characters before the cursor. a whole grapheme is deleted, unlike backspace
+                        // which deletes combining marks one by one
+                        if let Some(next) = text.next_grapheme_offset(selection.end) {
+                            *selection = Selection::new(selection.end, next);
+                            self.delete_backward();
+                        }
+                    } else {
+                        self.delete_backward();
+                    }
+                }
+            },
+        }
+    }
+
+    /// Edit a selection using a `Movement`.
+    fn move_selection(&mut self, mvmnt: Movement, modify: bool) {
+        match &mut self.cursor {
+            Cursor::Point { token, selection } => {
+                let mut index = *token;
+                let text = &self.tokens[index].str;
+                // left and right are visual, a right-to-left token starts on the right
+                let rtl = is_rtl(self.language, &self.tokens[index]);
+                let (left_edge, right_edge) = if rtl { (text.len(), 0) } else { (0, text.len()) };
+                // let self_separator = self.language.node(self.tokens[index].tp).as_token().is_separator();
+                // This movement function should ensure all movements are legit.
+                // If they aren't, that's a problem with the movement function.
+                match mvmnt {
+                    Movement::Left if selection.end == left_edge => {
+                        if index > 0 {
+                            index -= 1;
+                            let token_next = self.tokens[index].tp;
+                            if self.language.node(token_next).as_token().is_separator() {
+                                if index > 0 {
+                                    index -= 1;
+                                }
+                            }
+                            let offset = if is_rtl(self.language, &self.tokens[index]) { 0 } else { self.tokens[index].str.len() };
+                            self.cursor = Cursor::Point { token: index as usize, selection: Selection::caret(offset) }
+                        }
+                    },
+                    Movement::Right if selection.end == right_edge => {
+                        let mut index = *token;
+                        if index < self.tokens.len() - 1 {
+                            index += 1;
+                            let token_next = self.tokens[index].tp;
+                            if self.language.node(token_next).as_token().is_separator() {
+                                if index < self.tokens.len() - 1 {
+                                    index += 1;
+                                }
+                            }
+                            let offset = if is_rtl(self.language, &self.tokens[index]) { self.tokens[index].str.len() } else { 0 };
+                            self.cursor = Cursor::Point { token: index, selection: Selection::caret(offset) }
+                        }
+                    },
+                    Movement::Left if rtl => {
+                        *selection = text.movement(Movement::Right, *selection, modify);
+                    },
+                    Movement::Right if rtl => {
+                        *selection = text.movement(Movement::Left, *selection, modify);
+                    },
+                    _ => {
+                        *selection = text.movement(mvmnt, *selection, modify);
+                    }
+                }
+            },
+        }
+    }
+
+    pub fn cursor_point(&self) -> (usize, usize) {
+        match self.cursor {
+            Cursor::Point { token, selection } => (token, selection.end),
+        }
+    }
+
+    /// the token and the selected range in it
+    pub fn selection(&self) -> (usize, Range<usize>) {
+        match self.cursor {
+            Cursor::Point { token, selection } => (token, selection.range()),
+        }
+    }
+
+    /// place a caret, the cursor never sits on a separator or inside a grapheme
+    pub fn set_cursor(&mut self, token: usize, offset: usize) {
+        let mut token = token;
+        let mut offset = self.tokens[token].str.grapheme_boundary(offset);
+        if self.language.node(self.tokens[token].tp).as_token().is_separator() {
+            if token > 0 {
+                token -= 1;
+                offset = self.tokens[token].str.len();
+            } else if token < self.tokens.len() - 1 {
+                token += 1;
+                offset = 0;
+            }
+        }
+        self.cursor = Cursor::Point { token, selection: Selection::caret(offset) };
+        self.reveal_cursors();
+    }
+
+    /// the user types into a missing token by creating it first
+    fn fill_missing(&mut self, index: usize, tp: u16) {
+        let (token, offset) = match self.language.node(tp) {
+            NodeSpec::Token(TokenSpec::Constant { str, .. }) => (Token::new(tp, *str), str.len()),
+            NodeSpec::Token(spec) if spec.can_empty() => (Token::new(tp, ""), 0),
+            _ => (Token::new(self.language.lex_error(), ""), 0)
+        };
+        self.tokens.insert(index, token);
+        self.cursor = Cursor::Point { token: index, selection: Selection::caret(offset) };
+        self.reparse(index, index, index + 1);
+        self.fill_holes();
+    }
+
+    pub fn click(&mut self, hit: Hit) {
+        match hit {
+            Hit::Token(token, offset) => self.set_cursor(token, offset),
+            Hit::Missing(index, tp) => self.fill_missing(index, tp),
+            Hit::Fold(start) => {
+                if let Some(f) = self.fold_id(start) {
+                    self.folds.remove(&f);
+                }
+            }
+        }
+    }
+
+    /// IME pre-edit text at the primary cursor, it is only shown until the committed text is typed, see
+    /// `do_edit_action`
+    pub fn set_composition(&mut self, text: &str) {
+        self.composition = if text.is_empty() { None } else { Some(String::from(text)) };
+    }
+
+    /// the token and text offset where the pre-edit text is shown
+    pub fn composition(&self) -> Option<(usize, usize, &str)> {
+        let (token, offset) = self.cursor_point();
+        self.composition.as_ref().map(|c| (token, offset, c.as_str()))
+    }
+
+    /// each cursor takes turn to be the primary cursor, and the primary one is back at last
+    pub fn for_each_cursor(&mut self, mut f: impl FnMut(&mut EditorState)) {
+        for _ in 0..=self.cursors.len() {
+            f(self);
+            self.cursors.push(self.cursor.clone());
+            self.cursor = self.cursors.remove(0);
+        }
+        let mut unique: Vec<Cursor> = vec![];
+        for c in self.cursors.drain(..) {
+            if c != self.cursor && !unique.contains(&c) {
+                unique.push(c);
+            }
+        }
+        self.cursors = unique;
+    }
+
+    fn has_cursor_on(&self, token: usize) -> bool {
+        std::iter::once(&self.cursor).chain(self.cursors.iter()).any(|c| match c {
+            Cursor::Point { token: t, .. } => *t == token
+        })
+    }
+
+    /// the new cursor becomes the primary one
+    pub fn add_cursor(&mut self, token: usize, offset: usize) {
+        let old = self.cursor.clone();
+        self.set_cursor(token, offset);
+        if self.cursor != old {
+            self.cursors.push(old);
+        }
+    }
+
+    /// first select the token text, then add cursors to the following tokens with the same text
+    pub fn add_cursor_at_next_occurrence(&mut self) {
+        let (token, selection) = match &self.cursor {
+            Cursor::Point { token, selection } => (*token, *selection),
+        };
+        let text = &self.tokens[token].str;
+        let text_len = text.len();
+        if text.is_empty() {
+            return;
+        }
+        if selection.is_caret() {
+            self.cursor = Cursor::Point { token, selection: Selection::new(0, text_len) };
+            return;
+        }
+        let len = self.tokens.len();
+        let next = (1..len).map(|i| (token + i) % len).find(|&i| self.tokens[i].str == *text && !self.has_cursor_on(i));
+        if let Some(next) = next {
+            self.cursors.push(self.cursor.clone());
+            self.cursor = Cursor::Point { token: next, selection: Selection::new(0, text_len) };
+        }
+    }
+
+    pub fn clear_cursors(&mut self) {
+        self.cursors.clear();
+    }
+
+    pub fn do_edit_action(&mut self, edit_action: EditAction) {
+        // the committed text of a composition comes as typed text
+        self.composition = None;
+        match edit_action {
+            EditAction::Insert(_) | EditAction::Paste(_) | EditAction::Backspace | EditAction::Delete => self.checkpoint(),
+            _ => {}
+        }
+        self.for_each_cursor(|s| s.do_edit_action_at_cursor(&edit_action));
+        self.fill_holes();
+        self.reveal_cursors();
+    }
+
+    fn do_edit_action_at_cursor(&mut self, edit_action: &EditAction) {
+        match edit_action {
+            EditAction::Insert(chars)  => {
+                self.insert(chars);
+            },
+            // pasted text doesn't step over or close delimiters like typing does
+            EditAction::Paste(chars) => {
+                self.insert_text(chars);
+            },
+            EditAction::Backspace => {
+                self.delete_backward();
+            },
+            EditAction::Delete => {
+                self.delete_forward();
+            },
+            EditAction::Move(movement) => self.move_selection(*movement, false),
+            _ => {}
+            //EditAction::ModifySelection(movement) => self.move_selection(movement, true),
+            //EditAction::SelectAll => selection.all(),
+            // EditAction::Click(action) => {
+            //     if action.mods.shift() {
+            //         self.selection.end = action.column;
+            //     } else {
+            //         self.caret_to(text, action.column);
+            //     }
+            // }
+            //EditAction::Drag(action) => self.selection.end = action.column,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::languages::json::INSTANCE as JSON;
+
+    fn state(tokens: Tokens) -> EditorState {
+        EditorState::with_tokens(&JSON, tokens)
+    }
+
+    #[test]
+    fn complete_keeps_accepted_text() {
+        let mut s = state(vector![Token::new(5, "["), Token::new(JSON.lex_error(), "12"), Token::new(6, "]")]);
+        s.complete(1, 8);
+        assert_eq!((s.tokens[1].tp, s.tokens[1].str.to_string()), (8, String::from("12")));
+    }
+
+    #[test]
+    fn complete_clears_rejected_text() {
+        let mut s = state(vector![Token::new(5, "["), Token::new(JSON.lex_error(), "num"), Token::new(6, "]")]);
+        s.complete(1, 8);
+        assert_eq!((s.tokens[1].tp, s.tokens[1].str.to_string()), (8, String::new()));
+        assert_eq!(s.cursor_point(), (1, 0));
+    }
+
+    #[test]
+    fn paste_at_every_cursor() {
+        let mut s = state(vector![Token::new(5, "["), Token::new(7, "ab"), Token::new(2, ","), Token::new(7, "cd"), Token::new(6, "]")]);
+        s.set_cursor(1, 2);
+        s.add_cursor(3, 2);
+        s.do_edit_action(EditAction::Paste(String::from("x")));
+        assert_eq!((s.tokens[1].str.to_string(), s.tokens[3].str.to_string()), (String::from("abx"), String::from("cdx")));
+        // a single undo step
+        s.undo();
+        assert_eq!((s.tokens[1].str.to_string(), s.tokens[3].str.to_string()), (String::from("ab"), String::from("cd")));
+    }
+
+    #[test]
+    fn text_edit_changes_version() {
+        let mut s = state(vector![Token::new(5, "["), Token::new(7, "ab"), Token::new(6, "]")]);
+        s.set_cursor(1, 2);
+        let version = s.version;
+        s.do_edit_action(EditAction::Insert(String::from("c")));
+        assert_eq!(s.tokens[1].tp, 7);
+        assert!(s.version > version);
+    }
+
+    /// the kept token diagnostics are the same as computed from scratch
+    fn assert_diagnostics(s: &EditorState) {
+        let summary = |ds: &[Diagnostic]| ds.iter().map(|d| (d.range.clone(), d.message.clone())).collect::<Vec<_>>();
+        assert_eq!(summary(&s.diagnostics), summary(&diagnostics(s.language, &s.tokens, &s.tree)));
+    }
+
+    #[test]
+    fn incremental_diagnostics() {
+        let mut s = state(vector![
+            Token::new(5, "["),
+            Token::new(8, "1"),
+            Token::new(2, ","),
+            Token::new(9, "true"),
+            Token::new(2, ","),
+            Token::new(8, "3"),
+            Token::new(6, "]")
+        ]);
+        s.set_cursor(3, 4);
+        s.do_edit_action(EditAction::Insert(String::from("x")));
+        let (t, _) = s.cursor_point();
+        assert_eq!(s.tokens[t].tp, JSON.lex_error());
+        assert_diagnostics(&s);
+        assert_eq!(s.severities.at(t), Some(Severity::Error));
+        s.do_edit_action(EditAction::Insert(String::from("y")));
+        assert!(s.diagnostics.iter().any(|d| d.message == "`truexy` is not a valid token"));
+        assert_diagnostics(&s);
+        // the number can't be empty, so it is removed
+        s.set_cursor(1, 1);
+        s.do_edit_action(EditAction::Backspace);
+        assert_diagnostics(&s);
+        s.undo();
+        assert_diagnostics(&s);
+        let t = s.tokens.iter().position(|t| t.str.to_string() == "truexy").unwrap();
+        s.set_cursor(t, 6);
+        s.do_edit_action(EditAction::Backspace);
+        s.do_edit_action(EditAction::Backspace);
+        let (t, _) = s.cursor_point();
+        assert_eq!(s.tokens[t].tp, 9);
+        assert_diagnostics(&s);
+    }
+
+    #[test]
+    fn complete_constant() {
+        let mut s = state(vector![Token::new(5, "["), Token::new(JSON.lex_error(), "nu"), Token::new(6, "]")]);
+        s.complete(1, 11);
+        assert_eq!((s.tokens[1].tp, s.tokens[1].str.to_string()), (11, String::from("null")));
+        assert_eq!(s.cursor_point(), (1, 4));
+    }
+
+    #[test]
+    fn complete_stale_token() {
+        let mut s = state(vector![Token::new(5, "["), Token::new(JSON.lex_error(), "nu"), Token::new(6, "]")]);
+        let version = s.version;
+        s.complete(3, 11);
+        s.complete(1, 17);
+        assert_eq!((s.version, s.tokens[1].tp), (version, JSON.lex_error()));
+    }
+
+    #[test]
+    fn fold_follows_edits() {
+        let mut s = state(vector![
+            Token::new(5, "["), Token::new(8, "1"), Token::new(2, ","), Token::new(8, "2"), Token::new(2, ","),
+            Token::new(5, "["), Token::new(8, "3"), Token::new(6, "]"), Token::new(6, "]")
+        ]);
+        let inner = |s: &EditorState| (1..s.tokens.len()).find(|&i| s.tokens[i].tp == 5).unwrap();
+        s.toggle_fold(5);
+        assert!(s.is_folded(5));
+        // tokens before the fold are removed
+        s.set_cursor(1, 1);
+        s.do_edit_action(EditAction::Backspace);
+        assert!(inner(&s) < 5);
+        assert_eq!(s.folded().into_iter().collect::<Vec<_>>(), vec![inner(&s)]);
+        // and come back with undo, which relocates the folds by id
+        s.undo();
+        assert_eq!(inner(&s), 5);
+        assert_eq!(s.folded().into_iter().collect::<Vec<_>>(), vec![5]);
+    }
+
+    #[test]
+    fn node_range_after_insertion() {
+        let mut s = state(vector![
+            Token::new(5, "["), Token::new(8, "1"), Token::new(2, ","),
+            Token::new(5, "["), Token::new(8, "2"), Token::new(6, "]"), Token::new(6, "]")
+        ]);
+        let id = s.fold_id(3).unwrap();
+        let one = s.tokens[1].id;
+        assert_eq!(s.node_range(id), Some(3..6));
+        s.tokens.insert(1, Token::new(8, "0"));
+        s.tokens.insert(2, Token::new(2, ","));
+        s.reparse(1, 1, 3);
+        assert_eq!(s.node_range(id), Some(5..8));
+        assert_eq!(s.token_index(one), Some(3));
+        s.tokens.remove(3);
+        s.tokens.remove(3);
+        s.reparse(3, 5, 3);
+        assert_eq!(s.node_range(id), Some(3..6));
+        assert_eq!(s.token_index(one), None);
+    }
+
+    #[test]
+    fn typing_ends_composition() {
+        let mut s = state(vector![Token::new(5, "["), Token::new(7, "ab"), Token::new(6, "]")]);
+        s.set_cursor(1, 1);
+        s.set_composition("x");
+        assert_eq!(s.composition(), Some((1, 1, "x")));
+        s.do_edit_action(EditAction::Insert(String::from("y")));
+        assert_eq!(s.composition(), None);
+        assert_eq!(s.tokens[1].str.to_string(), "ayb");
+    }
+
+    #[test]
+    fn missing_end_becomes_hole() {
+        let mut s = state(vector![Token::new(1, "{"), Token::new(7, "k"), Token::new(4, ":"), Token::new(8, "1")]);
+        assert_eq!(s.tokens.len(), 5);
+        assert_eq!((s.tokens[4].tp, s.tokens[4].hole), (3, true));
+        assert!(!s.diagnostics.is_empty());
+        s.jump_to_hole(true);
+        assert_eq!(s.cursor_point(), (4, 0));
+        // typed text replaces the hole and is lexed
+        s.do_edit_action(EditAction::Insert(String::from("}")));
+        assert_eq!((s.tokens.len(), s.tokens[4].tp, s.tokens[4].hole), (5, 3, false));
+        assert_eq!(s.tokens[4].str.to_string(), "}");
+        assert!(s.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn step_over_keeps_hole_id() {
+        let mut s = state(vector![Token::new(5, "["), Token::new(8, "1"), Token::hole(6)]);
+        let id = s.tokens[2].id;
+        s.set_cursor(1, 1);
+        s.do_edit_action(EditAction::Insert(String::from("]")));
+        assert_eq!((s.tokens[2].id, s.tokens[2].hole, s.tokens[2].str.to_string()), (id, false, String::from("]")));
+        assert_eq!(s.cursor_point(), (2, 1));
+    }
+
+    #[test]
+    fn step_over_unless_accepted() {
+        let mut s = state(vector![Token::new(5, "["), Token::new(7, "abc"), Token::new(6, "]")]);
+        s.set_cursor(1, 3);
+        s.do_edit_action(EditAction::Insert(String::from("]")));
+        assert_eq!((s.tokens.len(), s.tokens[1].str.to_string()), (3, String::from("abc]")));
+        assert_eq!(s.cursor_point(), (1, 4));
+        let mut s = state(vector![Token::new(5, "["), Token::new(9, "true"), Token::new(6, "]")]);
+        s.set_cursor(1, 4);
+        s.do_edit_action(EditAction::Insert(String::from("]")));
+        assert_eq!((s.tokens.len(), s.tokens[1].str.to_string()), (3, String::from("true")));
+        assert_eq!(s.cursor_point(), (2, 1));
+    }
+}
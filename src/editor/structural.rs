@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use tree_sitter::{Node, Query, QueryCursor, Tree};
+
+use crate::*;
+
+// structural search uses tree-sitter queries, like
+// `(pair (string) @key (number) @value (#eq? @key "id"))`
+// the text of a node is the text of its only token, token texts don't have quotes
+
+/// token ranges of a query match
+#[derive(Clone, Debug)]
+pub struct TreeMatch {
+    /// the `@match` capture, or else the first capture, is what is replaced
+    pub range: Range<usize>,
+    pub captures: HashMap<String, Range<usize>>
+}
+
+/// predicates only compare nodes of a single token, which is not a large one
+fn node_text<'a>(tokens: &'a Tokens, node: Node) -> &'a [u8] {
+    if node.end_byte() == node.start_byte() + 1 {
+        tokens.get(node.start_byte()).and_then(|t| t.str.inline()).map_or(&[][..], |s| s.as_bytes())
+    } else {
+        &[]
+    }
+}
+
+/// matches sorted by position, a match inside an earlier one is dropped
+pub fn find_tree_matches(language: &Language, tokens: &Tokens, tree: &Tree, source: &str) -> Result<Vec<TreeMatch>, String> {
+    let query = Query::new(language.language(), source).map_err(|e| format!("invalid query: {:?}", e))?;
+    let names = query.capture_names();
+    let mut cursor = QueryCursor::new();
+    let mut res: Vec<TreeMatch> = vec![];
+    for m in cursor.matches(&query, tree.root_node(), |n| node_text(tokens, n)) {
+        let captures: HashMap<String, Range<usize>> = m.captures.iter()
+            .map(|c| (names[c.index as usize].clone(), c.node.start_byte()..c.node.end_byte()))
+            .collect();
+        let range = match captures.get("match") {
+            Some(r) => r.clone(),
+            None => match m.captures.first() {
+                Some(c) => c.node.start_byte()..c.node.end_byte(),
+                None => continue
+            }
+        };
+        if range.start < range.end {
+            res.push(TreeMatch { range, captures });
+        }
+    }
+    res.sort_by_key(|m| m.range.start);
+    let mut end = 0;
+    res.retain(|m| {
+        let keep = m.range.start >= end;
+        if keep {
+            end = m.range.end;
+        }
+        keep
+    });
+    Ok(res)
+}
+
+/// words of the template are lexed into tokens, `@name` copies the tokens of a capture, with new ids
+pub fn expand_template(language: &Language, tokens: &Tokens, m: &TreeMatch, template: &str) -> Tokens {
+    let mut res = Tokens::new();
+    for word in template.split_whitespace() {
+        if word.starts_with('@') {
+            if let Some(r) = m.captures.get(&word[1..]) {
+                res.extend(tokens.skip(r.start).take(r.end - r.start).iter().map(Token::copy));
+            }
+        } else {
+            let tp = language.try_lex(word).unwrap_or(language.lex_error());
+            res.push_back(Token { id: TokenId::next(), tp, str: TokenText::from(word), hole: false });
+        }
+    }
+    res
+}
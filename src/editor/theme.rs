@@ -0,0 +1,347 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use druid::Color;
+use serde::Deserialize;
+
+use crate::*;
+
+// a theme file is toml, everything is optional and falls back to the built-in theme
+//
+// [font]
+// family = "JetBrains Mono"
+// size = 14.0
+//
+// [colors]
+// background = "#2b2b2b"
+// cursor = "#ffffff"
+// rainbow = ["#ffd700", "#da70d6", "#179fff"]
+//
+// [semantics]
+// keyword = "#cc7837"
+//
+// [categories]
+// "string.key" = "#9876aa"
+//
+// [tokens]
+// number = "#6897bb"
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ThemeFile {
+    font: FontFile,
+    colors: HashMap<String, ColorValue>,
+    semantics: HashMap<String, String>,
+    categories: HashMap<String, String>,
+    tokens: HashMap<String, String>
+}
+
+/// only `rainbow` is a list
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ColorValue {
+    Single(String),
+    List(Vec<String>)
+}
+
+/// `weight` and `italic` are only read to warn they are ignored
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct FontFile {
+    family: Option<String>,
+    size: Option<f64>,
+    weight: Option<String>,
+    italic: Option<bool>
+}
+
+/// piet only loads fonts by family name, it can't select a weight or style
+#[derive(Clone, Debug)]
+pub struct FontStyle {
+    pub family: String,
+    pub size: f64
+}
+
+#[derive(Clone, Debug)]
+pub struct Theme {
+    pub font: FontStyle,
+    pub background: Color,
+    pub foreground: Color,
+    pub cursor: Color,
+    pub selection: Color,
+    pub line_number: Color,
+    pub line_number_active: Color,
+    pub guide: Color,
+    pub guide_active: Color,
+    pub matching_delimiter: Color,
+    pub enclosing_node: Color,
+    /// the box of an empty token
+    pub slot: Color,
+    /// the text of missing tokens, holes and folds
+    pub placeholder: Color,
+    pub hole_background: Color,
+    pub error_background: Color,
+    pub search_match: Color,
+    pub search_match_current: Color,
+    /// structural search highlights the whole tree match
+    pub tree_match: Color,
+    pub tree_match_current: Color,
+    pub warning: Color,
+    pub error: Color,
+    pub panel: Color,
+    /// delimiters by nesting depth, empty to use the token colors
+    pub rainbow: Vec<Color>,
+    /// by the name of `ConstantTokenSemantics` or `RegexTokenSemantics` in snake case
+    semantics: HashMap<String, Color>,
+    /// dotted semantic categories, wins over semantics
+    categories: HashMap<String, Color>,
+    /// by token name, wins over everything
+    tokens: HashMap<String, Color>
+}
+
+/// `#rrggbb` or `#rrggbbaa`
+pub fn parse_color(str: &str) -> Result<Color, String> {
+    let hex = str.trim().trim_start_matches('#');
+    let byte = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("invalid color `{}`", str));
+    if !hex.is_ascii() {
+        return Err(format!("invalid color `{}`", str));
+    }
+    match hex.len() {
+        6 => Ok(Color::rgb8(byte(0)?, byte(2)?, byte(4)?)),
+        8 => Ok(Color::rgba8(byte(0)?, byte(2)?, byte(4)?, byte(6)?)),
+        _ => Err(format!("invalid color `{}`", str))
+    }
+}
+
+fn semantics_name(tp: &TokenSpec) -> &'static str {
+    match tp {
+        TokenSpec::Constant { semantics, .. } => match semantics {
+            ConstantTokenSemantics::Separator => "separator",
+            ConstantTokenSemantics::Delimiter => "delimiter",
+            ConstantTokenSemantics::Keyword => "keyword",
+        },
+        TokenSpec::Regex { semantics, .. } => match semantics {
+            RegexTokenSemantics::Literal => "literal",
+            RegexTokenSemantics::Unspecified => "unspecified",
+            RegexTokenSemantics::LexingError => "lexing_error",
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        let semantics = vec![
+            ("separator", Color::rgb8(169, 0, 198)),
+            ("delimiter", Color::rgb8(0, 183, 198)),
+            ("keyword", Color::rgb8(204, 120, 55)),
+            ("literal", Color::rgb8(106, 135, 89)),
+            ("unspecified", Color::rgb8(169, 183, 198)),
+            ("lexing_error", Color::rgb8(255, 0, 0)),
+        ];
+        let categories = vec![
+            (category::KEYWORD, Color::rgb8(204, 120, 55)),
+            (category::CONSTANT, Color::rgb8(204, 120, 55)),
+            (category::NUMBER, Color::rgb8(104, 151, 187)),
+            (category::STRING, Color::rgb8(106, 135, 89)),
+            ("string.key", Color::rgb8(152, 118, 170)),
+            (category::PROPERTY, Color::rgb8(152, 118, 170)),
+            (category::TYPE, Color::rgb8(190, 145, 23)),
+            (category::IDENTIFIER, Color::rgb8(169, 183, 198)),
+            (category::OPERATOR, Color::rgb8(169, 183, 198)),
+            (category::INVALID, Color::rgb8(255, 0, 0)),
+        ];
+        Theme {
+            font: FontStyle { family: String::from("JetBrains Mono"), size: 14.0 },
+            background: Color::rgb8(43, 43, 43),
+            foreground: Color::grey8(220),
+            cursor: Color::grey8(255),
+            selection: Color::rgba8(33, 66, 131, 200),
+            line_number: Color::grey8(90),
+            line_number_active: Color::grey8(200),
+            guide: Color::grey8(60),
+            guide_active: Color::grey8(140),
+            matching_delimiter: Color::rgba8(59, 81, 77, 255),
+            enclosing_node: Color::rgba8(255, 255, 255, 10),
+            slot: Color::grey8(110),
+            placeholder: Color::grey8(110),
+            hole_background: Color::rgba8(255, 255, 255, 24),
+            error_background: Color::rgba8(255, 0, 0, 48),
+            search_match: Color::rgba8(255, 196, 0, 50),
+            search_match_current: Color::rgba8(255, 196, 0, 110),
+            tree_match: Color::rgba8(255, 196, 0, 30),
+            tree_match_current: Color::rgba8(255, 196, 0, 70),
+            warning: Color::rgb8(190, 145, 23),
+            error: Color::rgb8(255, 0, 0),
+            panel: Color::rgb8(60, 63, 65),
+            rainbow: vec![Color::rgb8(255, 215, 0), Color::rgb8(218, 112, 214), Color::rgb8(23, 159, 255)],
+            semantics: semantics.into_iter().map(|(n, c)| (String::from(n), c)).collect(),
+            categories: categories.into_iter().map(|(n, c)| (String::from(n), c)).collect(),
+            tokens: HashMap::new()
+        }
+    }
+}
+
+impl Theme {
+    /// the file is applied on top of the built-in theme
+    pub fn parse(source: &str) -> Result<Theme, String> {
+        let file: ThemeFile = toml::from_str(source).map_err(|e| e.to_string())?;
+        let mut theme = Theme::default();
+        if let Some(family) = file.font.family {
+            theme.font.family = family;
+        }
+        if let Some(size) = file.font.size {
+            theme.font.size = size;
+        }
+        // piet can't select a face by these yet, the rest of the file still applies
+        if file.font.weight.is_some() || file.font.italic.is_some() {
+            eprintln!("theme: font `weight` and `italic` are ignored, use the family name of the face");
+        }
+        for (name, color) in &file.colors {
+            let color = match (name.as_str(), color) {
+                ("rainbow", ColorValue::List(colors)) => {
+                    theme.rainbow = colors.iter().map(|c| parse_color(c)).collect::<Result<_, _>>()?;
+                    continue;
+                },
+                (_, ColorValue::Single(color)) => parse_color(color)?,
+                (n, _) => return Err(format!("`{}` should be a single color", n))
+            };
+            let field = match name.as_str() {
+                "background" => &mut theme.background,
+                "foreground" => &mut theme.foreground,
+                "cursor" => &mut theme.cursor,
+                "selection" => &mut theme.selection,
+                "line_number" => &mut theme.line_number,
+                "line_number_active" => &mut theme.line_number_active,
+                "guide" => &mut theme.guide,
+                "guide_active" => &mut theme.guide_active,
+                "matching_delimiter" => &mut theme.matching_delimiter,
+                "enclosing_node" => &mut theme.enclosing_node,
+                "slot" => &mut theme.slot,
+                "placeholder" => &mut theme.placeholder,
+                "hole_background" => &mut theme.hole_background,
+                "error_background" => &mut theme.error_background,
+                "search_match" => &mut theme.search_match,
+                "search_match_current" => &mut theme.search_match_current,
+                "tree_match" => &mut theme.tree_match,
+                "tree_match_current" => &mut theme.tree_match_current,
+                "warning" => &mut theme.warning,
+                "error" => &mut theme.error,
+                "panel" => &mut theme.panel,
+                n => return Err(format!("unknown color `{}`", n))
+            };
+            *field = color;
+        }
+        for (name, color) in &file.semantics {
+            theme.semantics.insert(name.clone(), parse_color(color)?);
+        }
+        for (name, color) in &file.categories {
+            theme.categories.insert(name.clone(), parse_color(color)?);
+        }
+        for (name, color) in &file.tokens {
+            theme.tokens.insert(name.clone(), parse_color(color)?);
+        }
+        Ok(theme)
+    }
+
+    /// the longest known prefix of a dotted category
+    fn category_color(&self, category: &str) -> Option<&Color> {
+        let mut category = category;
+        loop {
+            if let Some(c) = self.categories.get(category) {
+                return Some(c);
+            }
+            match category.rfind('.') {
+                Some(i) => category = &category[..i],
+                None => return None
+            }
+        }
+    }
+
+    /// `category` is the category of the token in context
+    pub fn token_color(&self, tp: &TokenSpec, category: &str) -> Color {
+        self.tokens.get(tp.name())
+            .or_else(|| self.category_color(category))
+            .or_else(|| self.semantics.get(semantics_name(tp)))
+            .cloned()
+            .unwrap_or_else(|| self.foreground.clone())
+    }
+
+    /// `NOLEX_THEME`, or `theme.toml` in the working directory
+    pub fn path() -> PathBuf {
+        std::env::var_os("NOLEX_THEME").map_or(PathBuf::from("theme.toml"), PathBuf::from)
+    }
+
+    /// a missing file is the built-in theme, an invalid one is reported and ignored
+    pub fn load() -> Theme {
+        match std::fs::read_to_string(Theme::path()) {
+            Ok(source) => Theme::parse(&source).unwrap_or_else(|e| {
+                eprintln!("theme: {}", e);
+                Theme::default()
+            }),
+            Err(_) => Theme::default()
+        }
+    }
+}
+
+/// polls the modification time of the theme file
+pub struct ThemeWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>
+}
+
+impl ThemeWatcher {
+    pub fn new() -> ThemeWatcher {
+        let path = Theme::path();
+        let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+        ThemeWatcher { path, modified }
+    }
+
+    /// the new theme if the file is changed since the last poll
+    pub fn poll(&mut self) -> Option<Theme> {
+        let modified = std::fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+        match std::fs::read_to_string(&self.path) {
+            Ok(source) => match Theme::parse(&source) {
+                Ok(theme) => Some(theme),
+                Err(e) => {
+                    eprintln!("theme: {}", e);
+                    None
+                }
+            },
+            Err(_) => Some(Theme::default())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paint_colors() {
+        let theme = Theme::parse("[colors]\nerror_background = \"#ff000030\"\nplaceholder = \"#6e6e6e\"\nsearch_match_current = \"#ffc4006e\"").unwrap();
+        assert_eq!(theme.error_background.as_rgba_u32(), 0xff000030);
+        assert_eq!(theme.placeholder.as_rgba_u32(), 0x6e6e6eff);
+        assert_eq!(theme.search_match_current.as_rgba_u32(), 0xffc4006e);
+    }
+
+    #[test]
+    fn unknown_color() {
+        assert!(Theme::parse("[colors]\nsearch = \"#ffffff\"").is_err());
+    }
+
+    #[test]
+    fn font_family() {
+        let theme = Theme::parse("[font]\nfamily = \"Fira Code\"\nsize = 12.0").unwrap();
+        assert_eq!((theme.font.family.as_str(), theme.font.size), ("Fira Code", 12.0));
+    }
+
+    #[test]
+    fn font_style_ignored() {
+        let theme = Theme::parse("[font]\nfamily = \"Fira Code\"\nweight = \"bold\"\nitalic = true\n[colors]\npanel = \"#102030\"").unwrap();
+        assert_eq!(theme.font.family, "Fira Code");
+        assert_eq!(theme.panel.as_rgba_u32(), 0x102030ff);
+    }
+}
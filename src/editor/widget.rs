@@ -1,344 +1,188 @@
-use druid::piet::{FontBuilder, Text, TextLayoutBuilder, TextLayout, PietFont, PietText};
+use druid::piet::{FontBuilder, Text, TextLayoutBuilder, TextLayout, PietFont, PietText, PietTextLayout};
 use druid::widget::prelude::*;
-use druid::{Point, Color, Rect};
-use druid::text::{Selection, BasicTextInput, TextInput, EditAction, EditableText, offset_for_delete_backwards, Movement, movement};
-use tree_sitter::{Parser, Node, Tree, InputEdit};
-use druid::im::vector;
+use druid::{Point, Color, Rect, HotKey, KeyCode, SysMods, RawMods, TimerToken, Selector};
+use std::time::Duration;
+use druid::kurbo::BezPath;
+use unicode_segmentation::UnicodeSegmentation;
+use druid::text::{BasicTextInput, TextInput, EditAction};
+use tree_sitter::{Node, Tree};
+use std::collections::BTreeSet;
 
 use crate::*;
 
 // TODO partial layout by using layout focus & offset etc. handle scroll ourselves
 // TODO reuse text layout for commonly created strs with same attribute?
 
-#[derive(Clone, Debug)]
-enum Cursor {
-    Point {
-        token: usize,
-        selection: Selection // TODO blinking!
-    }
+const BLINK: Duration = Duration::from_millis(500);
+
+/// druid doesn't deliver IME events yet, the platform glue submits the pre-edit text as a `String` each time it
+/// changes, like `Command::new(SET_COMPOSITION, text)`, and an empty text to cancel. the committed text is not
+/// submitted, it comes as key events with text, which goes through `BasicTextInput` like any typing and ends the
+/// composition
+pub const SET_COMPOSITION: Selector = Selector::new("nolex.set-composition");
+
+/// line numbers and fold markers are shown on the left
+const GUTTER: f64 = 56.0;
+const FOLD_MARKER_X: f64 = GUTTER - 18.0;
+
+pub struct EditorWidget {
+    basic: BasicTextInput,
+    font: Option<PietFont>,
+    theme: Theme,
+    theme_watcher: ThemeWatcher,
+    theme_timer: TimerToken,
+    blink_timer: TimerToken,
+    caret_visible: bool,
+    max_width: f64,
+
+    data: Option<EditorState>,
+    layout: Vec<Line>,
+    /// start delimiters of the tree nodes laid out in multiple lines or folded
+    foldable: BTreeSet<usize>,
+    guides: Vec<Guide>,
+
+    /// mouse position and the hovered diagnostic
+    hover: Option<(Point, usize)>,
+    show_problems: bool,
+    /// where the entries of problems list are painted
+    problem_rects: Vec<(Rect, usize)>,
+    completion: Option<Completion>,
+    search: Option<SearchPanel>,
 }
 
-impl PartialEq for Cursor {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Cursor::Point {token: t1, selection: s1}, Cursor::Point { token: t2, selection: s2}) =>
-                t1 == t2 && s1.start == s2.start && s1.end == s2.end,
-            _ => false
-        }
+impl EditorWidget {
+    pub fn new() -> EditorWidget {
+        let state = EditorWidget {
+            basic: BasicTextInput::new(), data: None,
+            font: None, theme: Theme::load(), theme_watcher: ThemeWatcher::new(), theme_timer: TimerToken::INVALID,
+            blink_timer: TimerToken::INVALID, caret_visible: true,
+            layout: vec![], foldable: BTreeSet::new(), guides: vec![], max_width: 0.0,
+            hover: None, show_problems: false, problem_rects: vec![],
+            completion: None, search: None,
+        };
+        state
     }
 }
 
-impl Eq for Cursor {}
+fn severity_color(theme: &Theme, severity: Severity) -> Color {
+    match severity {
+        Severity::Warning => theme.warning.clone(),
+        Severity::Error => theme.error.clone()
+    }
+}
 
+fn squiggle(x0: f64, x1: f64, y: f64) -> BezPath {
+    let mut path = BezPath::new();
+    path.move_to((x0, y));
+    let mut x = x0;
+    let mut up = true;
+    while x < x1 {
+        x = (x + 2.0).min(x1);
+        path.line_to((x, if up { y - 2.0 } else { y }));
+        up = !up;
+    }
+    path
+}
 
-pub struct EditorState {
-    version: u64,
-    language: &'static Language,
-    tokens: Tokens,
-    cursor: Cursor,
-    parser: Parser,
-    tree: Tree
+fn vertical_target(layout: &[Line], token: usize, offset: usize, down: bool) -> Option<(usize, usize)> {
+    let (line, x) = cursor_position(layout, token, offset)?;
+    let target = if down {
+        line + 1
+    } else if line > 0 {
+        line - 1
+    } else {
+        return None
+    };
+    // moving onto a missing token doesn't create it
+    match layout.get(target).and_then(|l| l.hit_test(x)) {
+        Some(Hit::Token(token, offset)) => Some((token, offset)),
+        _ => None
+    }
 }
 
-const tree_sitter_point_zero: tree_sitter::Point = tree_sitter::Point { row: 0, column: 0 };
-const tree_sitter_point_one: tree_sitter::Point = tree_sitter::Point { row: 0, column: 1 };
+impl EditorWidget {
+    fn data(&self) -> &EditorState {
+        self.data.as_ref().unwrap()
+    }
 
-impl EditorState {
-    pub fn new() -> EditorState {
-        let tokens = vector![
-            Token::new(1, "{"),
-            Token::new(7, "key2"),
-            Token::new(4, ":"),
-            Token::new(7, "valuevaluevalueأَلْحُرُوف ٱلْعَرَبِيَّة😄😁😆 value valul"),
-            Token::new(2, ","),
-            Token::new(7, "key"),
-            Token::new(4, ":"),
-            Token::new(5, "["),
-            Token::new(7, "key"),
-            Token::new(2, ","),
-            Token::new(7, "key"),
-            Token::new(2, ","),
-            Token::new(7, "key"),
-            Token::new(2, ","),
-            Token::new(7, "key"),
-            Token::new(2, ","),
-            Token::new(7, "key"),
-            Token::new(2, ","),
-            Token::new(7, "key"),
-            Token::new(2, ","),
-            Token::new(7, "key"),
-            Token::new(2, ","),
-            Token::new(7, "key"),
-            Token::new(2, ","),
-            Token::new(7, "key"),
-            Token::new(2, ","),
-            Token::new(7, "key"),
-            Token::new(2, ","),
-            Token::new(7, "key"),
-            Token::new(6, "]"),
-            Token::new(2, ","),
-            Token::new(7, "key"),
-            Token::new(4, ":"),
-            Token::new(8, "1000"),
-            Token::new(2, ","),
-            Token::new(7, "key3"),
-            Token::new(4, ":"),
-            Token::new(11, "true"),
-            Token::new(3, "}")
-        ];
-        let cursor = Cursor::Point { token: 0, selection: Selection { start : 0, end : 0 } };
-        let language: &'static Language = &crate::languages::json::INSTANCE;
-
-        let mut parser = Parser::new();
-        parser.set_language(language.language()).unwrap();
-        // TODO bad
-        let tps: Vec<u8> = tokens.iter().map(|n| n.tp as u8).collect();
-        let tree = parser.parse(&tps, None).unwrap();
-        EditorState {
-            version: 0,
-            language,
-            tokens, cursor, tree, parser
-        }
-    }
-
-    fn reparse(&mut self, start: usize, end: usize, new_end: usize) {
-        self.version += 1;
-        self.tree.edit(&InputEdit {
-            start_byte: start,
-            old_end_byte: end,
-            new_end_byte: new_end,
-            start_position: tree_sitter_point_zero,
-            old_end_position: tree_sitter_point_one,
-            new_end_position: tree_sitter_point_one
-        });
-        // TODO bad
-        let tps: Vec<u8> = self.tokens.iter().map(|n| n.tp as u8).collect();
-        self.tree = self.parser.parse(tps, Some(&self.tree)).unwrap();
-        if self.tokens.is_empty() {
-            self.tokens.push_back(Token::new(self.language.lex_error(), ""));
-            self.reparse(0, 0, 1)
-        } else if self.language.node(self.tokens[0].tp).as_token().is_separator() {
-            self.tokens.push_front(Token::new(self.language.lex_error(), ""));
-            self.reparse(0, 0, 1)
-        } else if self.language.node(self.tokens.last().unwrap().tp).as_token().is_separator() {
-            let len = self.tokens.len();
-            self.tokens.push_back(Token::new(self.language.lex_error(), ""));
-            self.reparse(len, len, len + 1);
-        }
-    }
-
-    fn lex_sync_then_sit(&mut self, t: usize) {
-        let token = &mut self.tokens[t];
-        let spec = self.language.node(token.tp).as_token();
-        if token.str.is_empty() {
-            if !spec.can_empty() {
-                self.move_selection(Movement::Left, false);
-                self.tokens.remove(t);
-                self.reparse(t, t + 1, t);
-            }
-        } else {
-            if spec.is_lex_error() {
-                if let Some(tp) = self.language.try_lex(&token.str) {
-                    token.tp = tp;
-                    self.reparse(t, t + 1, t + 1);
-                }
-            } else {
-                if !spec.accept(&token.str) {
-                    token.tp = self.language.lex_error();
-                    self.reparse(t, t + 1, t + 1);
-                }
-            }
+    /// show the pre-edit text, an empty text cancels the composition, see `SET_COMPOSITION`
+    fn set_composition(&mut self, ctx: &mut EventCtx, text: &str) {
+        if let Some(data) = &mut self.data {
+            data.set_composition(text);
+            ctx.request_layout();
+            ctx.request_paint();
         }
     }
 
-    fn insert(&mut self, new: &str) {
-        match &mut self.cursor {
-            Cursor::Point { token, selection } => {
-                let token = *token;
-                let text = &mut self.tokens[token].str;
-                *selection = selection.constrain_to(text);
-                text.edit(selection.range(), new);
-                *selection = Selection::caret(selection.min() + new.len());
-                self.lex_sync_then_sit(token);
-            },
-        }
+    /// the foldable tree node starts on the line
+    fn fold_marker(&self, line: &Line) -> Option<usize> {
+        line.positions().map(|(_, t)| t).find(|t| !t.is_missing() && !t.is_fold() && self.foldable.contains(&t.index())).map(|t| t.index())
     }
 
-    fn delete_backward(&mut self) {
-        match &mut self.cursor {
-            Cursor::Point { token, selection } => {
-                let token = *token;
-                let text = &mut self.tokens[token].str;
-                if text.is_empty() {
-                    self.move_selection(Movement::Left, false);
-                    self.tokens.remove(token);
-                    self.reparse(token, token + 1, token);
-                } else {
-                    let to = if selection.is_caret() {
-                        let cursor = selection.end;
-                        let new_cursor = offset_for_delete_backwards(&selection, text);
-                        text.edit(new_cursor..cursor, "");
-                        new_cursor
-                    } else {
-                        text.edit(selection.range(), "");
-                        selection.min()
-                    };
-                    match text.cursor(to) {
-                        Some(_) => *selection = Selection::caret(to),
-                        None => panic!()
-                    }
-                    self.lex_sync_then_sit(token);
-                }
-            },
+    /// select the next or previous search match
+    fn search_step(&mut self, forward: bool) {
+        let data = self.data.as_mut().unwrap();
+        if let Some(search) = &mut self.search {
+            let (token, selection) = data.selection();
+            if let Some(m) = search.step(token, selection, forward).cloned() {
+                data.select(m.token, m.range);
+            }
         }
     }
 
-    fn delete_forward(&mut self) {
-        match &mut self.cursor {
-            Cursor::Point { token, selection } => {
-                let token = *token;
-                let text = &mut self.tokens[token].str;
-                if text.is_empty() {
-
-                    self.tokens.remove(token);
-                    self.reparse(token, token + 1, token);
+    /// replace the current match or all of them
+    fn search_replace(&mut self, all: bool) {
+        self.completion = None;
+        let data = self.data.as_mut().unwrap();
+        if let Some(search) = &mut self.search {
+            if search.query.structural {
+                let matches = if all {
+                    search.tree_matches.clone()
                 } else {
-                    if selection.is_caret() {
-                        // Never touch the characters before the cursor.
-                        if text.next_grapheme_offset(selection.end).is_some() {
-                            self.move_selection(Movement::Right, false);
-                            self.delete_backward();
-                        }
-                    } else {
-                        self.delete_backward();
-                    }
+                    search.tree_matches.get(search.current).cloned().into_iter().collect()
+                };
+                data.replace_tree(&matches, &search.replace);
+            } else {
+                let matches = if all {
+                    search.matches.clone()
+                } else {
+                    search.matches.get(search.current).cloned().into_iter().collect()
+                };
+                if let Err(e) = data.replace(&matches, &search.query, &search.replace) {
+                    search.error = Some(e);
                 }
-            },
+            }
         }
-    }
-
-    /// Edit a selection using a `Movement`.
-    fn move_selection(&mut self, mvmnt: Movement, modify: bool) {
-        match &mut self.cursor {
-            Cursor::Point { token, selection } => {
-                let mut index = *token;
-                let text = &self.tokens[index].str;
-                // let self_separator = self.language.node(self.tokens[index].tp).as_token().is_separator();
-                // This movement function should ensure all movements are legit.
-                // If they aren't, that's a problem with the movement function.
-                match mvmnt {
-                    Movement::Left if selection.end == 0 => {
-                        if index > 0 {
-                            index -= 1;
-                            let token_next = self.tokens[index].tp;
-                            if self.language.node(token_next).as_token().is_separator() {
-                                if index > 0 {
-                                    index -= 1;
-                                }
-                            }
-                            self.cursor = Cursor::Point { token: index as usize, selection: Selection::caret(self.tokens[index].str.len()) }
-                        }
-                    },
-                    Movement::Right if selection.end == text.len() => {
-                        let mut index = *token;
-                        if index < self.tokens.len() - 1 {
-                            index += 1;
-                            let token_next = self.tokens[index].tp;
-                            if self.language.node(token_next).as_token().is_separator() {
-                                if index < self.tokens.len() - 1 {
-                                    index += 1;
-                                }
-                            }
-                            self.cursor = Cursor::Point { token: index, selection: Selection::caret(0) }
-                        }
-                    },
-                    _ => {
-                        *selection = movement(mvmnt, *selection, text, modify);
-                    }
-                }
-            },
+        if !all {
+            self.refresh_search();
+            self.search_step(true);
         }
     }
 
-    fn do_edit_action(&mut self, edit_action: EditAction) {
-        match edit_action {
-            EditAction::Insert(chars)  => {
-                self.insert(&chars);
-            },
-            // | EditAction::Paste(chars)
-            EditAction::Backspace => {
-                self.delete_backward();
-            },
-            EditAction::Delete => {
-                self.delete_forward();
-            },
-            EditAction::Move(movement) => self.move_selection(movement, false),
-            _ => {}
-            //EditAction::ModifySelection(movement) => self.move_selection(movement, true),
-            //EditAction::SelectAll => selection.all(),
-            // EditAction::Click(action) => {
-            //     if action.mods.shift() {
-            //         self.selection.end = action.column;
-            //     } else {
-            //         self.caret_to(text, action.column);
-            //     }
-            // }
-            //EditAction::Drag(action) => self.selection.end = action.column,
+    fn refresh_search(&mut self) {
+        let data = self.data.as_ref().unwrap();
+        if let Some(search) = &mut self.search {
+            search.refresh(data.version, data.language, &data.tokens, &data.tree);
         }
     }
-}
 
-pub struct EditorWidget {
-    basic: BasicTextInput,
-    font: Option<PietFont>,
-    max_width: f64,
-
-    data: Option<EditorState>,
-    layout: Vec<Line>,
-}
-
-impl EditorWidget {
-    pub fn new() -> EditorWidget {
-        let state = EditorWidget {
-            basic: BasicTextInput::new(), data: None,
-            font: None, layout: vec![], max_width: 0.0,
-        };
-        state
-    }
-}
-
-fn style(tp: &TokenSpec) -> Color {
-    match tp {
-        TokenSpec::Constant { semantics, .. } =>
-            match semantics {
-                ConstantTokenSemantics::Separator => {
-                    Color::rgb8(169, 0, 198)
-                },
-                ConstantTokenSemantics::Delimiter => {
-                    Color::rgb8(0, 183, 198)
-                },
-                ConstantTokenSemantics::Keyword => {
-                    Color::rgb8(204, 120, 55)
-                },
-            },
-        TokenSpec::Regex { semantics, .. } =>
-            match semantics {
-                RegexTokenSemantics::Literal => {
-                    Color::rgb8(106, 135, 89)
-                },
-                RegexTokenSemantics::Unspecified => {
-                    Color::rgb8(169, 183, 198)
-                },
-                RegexTokenSemantics::LexingError => {
-                    Color::rgb8(255, 0, 0)
-                }
+    /// keep the x position and go to the line above or below, this is where wrapped fragments matters
+    fn move_vertical(&mut self, down: bool) {
+        let layout = &self.layout;
+        self.data.as_mut().unwrap().for_each_cursor(|data| {
+            let (token, offset) = data.cursor_point();
+            if let Some((token, offset)) = vertical_target(layout, token, offset, down) {
+                data.set_cursor(token, offset);
             }
+        });
     }
-}
 
-impl EditorWidget {
-    fn data(&self) -> &EditorState {
-        self.data.as_ref().unwrap()
+    fn add_cursor_vertical(&mut self, down: bool) {
+        let data = self.data.as_mut().unwrap();
+        let (token, offset) = data.cursor_point();
+        if let Some((token, offset)) = vertical_target(&self.layout, token, offset, down) {
+            data.add_cursor(token, offset);
+        }
     }
 }
 impl Widget<u64> for EditorWidget {
@@ -359,10 +203,223 @@ impl Widget<u64> for EditorWidget {
 
     fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut u64, env: &Env) {
         match event {
+            Event::WindowConnected => {
+                self.theme_timer = ctx.request_timer(Duration::from_secs(1));
+                self.blink_timer = ctx.request_timer(BLINK);
+            },
+            Event::Timer(token) if *token == self.blink_timer => {
+                self.caret_visible = !self.caret_visible;
+                self.blink_timer = ctx.request_timer(BLINK);
+                ctx.request_paint();
+            },
+            Event::Timer(token) if *token == self.theme_timer => {
+                if let Some(theme) = self.theme_watcher.poll() {
+                    self.theme = theme;
+                    self.font = None;
+                    ctx.request_layout();
+                    ctx.request_paint();
+                }
+                self.theme_timer = ctx.request_timer(Duration::from_secs(1));
+            },
+            Event::Command(cmd) if cmd.selector == SET_COMPOSITION => {
+                if let Ok(text) = cmd.get_object::<String>() {
+                    self.set_composition(ctx, text);
+                }
+            },
+            Event::KeyDown(key_event) if self.completion.is_some() && HotKey::new(None, KeyCode::ArrowUp).matches(key_event) => {
+                self.completion.as_mut().unwrap().select(-1);
+                ctx.request_paint();
+            },
+            Event::KeyDown(key_event) if self.completion.is_some() && HotKey::new(None, KeyCode::ArrowDown).matches(key_event) => {
+                self.completion.as_mut().unwrap().select(1);
+                ctx.request_paint();
+            },
+            Event::KeyDown(key_event) if self.completion.is_some() &&
+                (HotKey::new(None, KeyCode::Return).matches(key_event) || HotKey::new(None, KeyCode::Tab).matches(key_event)) => {
+                let completion = self.completion.take().unwrap();
+                self.data.as_mut().unwrap().complete(completion.token, completion.current());
+                ctx.request_paint();
+                ctx.request_layout();
+            },
+            Event::KeyDown(key_event) if self.completion.is_some() && HotKey::new(None, KeyCode::Escape).matches(key_event) => {
+                self.completion = None;
+                ctx.request_paint();
+            },
+            Event::KeyDown(key_event) if HotKey::new(SysMods::Cmd, KeyCode::KeyF).matches(key_event) => {
+                let data = self.data();
+                let (token, selection) = data.selection();
+                let selected = data.tokens[token].str.slice(selection).into_owned();
+                match &mut self.search {
+                    Some(search) => {
+                        if !selected.is_empty() {
+                            search.query.text = selected;
+                        }
+                        search.focus = SearchField::Query;
+                    },
+                    None => self.search = Some(SearchPanel::new(selected))
+                }
+                ctx.request_paint();
+            },
+            Event::KeyDown(key_event) if HotKey::new(SysMods::Cmd, KeyCode::KeyZ).matches(key_event) => {
+                self.data.as_mut().unwrap().undo();
+                self.completion = None;
+                ctx.request_paint();
+                ctx.request_layout();
+            },
+            Event::KeyDown(key_event) if HotKey::new(SysMods::CmdShift, KeyCode::KeyZ).matches(key_event) => {
+                self.data.as_mut().unwrap().redo();
+                self.completion = None;
+                ctx.request_paint();
+                ctx.request_layout();
+            },
+            Event::KeyDown(key_event) if self.search.is_some() => {
+                let search = self.search.as_mut().unwrap();
+                if HotKey::new(None, KeyCode::Escape).matches(key_event) {
+                    self.search = None;
+                } else if HotKey::new(None, KeyCode::Tab).matches(key_event) {
+                    search.toggle_focus();
+                } else if HotKey::new(RawMods::Alt, KeyCode::KeyR).matches(key_event) {
+                    search.query.regex = !search.query.regex;
+                } else if HotKey::new(RawMods::Alt, KeyCode::KeyS).matches(key_event) {
+                    search.query.structural = !search.query.structural;
+                } else if HotKey::new(RawMods::Alt, KeyCode::KeyK).matches(key_event) {
+                    search.cycle_scope(self.data.as_ref().unwrap().language);
+                } else if HotKey::new(SysMods::Cmd, KeyCode::Return).matches(key_event) {
+                    self.search_replace(true);
+                    ctx.request_layout();
+                } else if HotKey::new(RawMods::Alt, KeyCode::Return).matches(key_event) {
+                    self.search_replace(false);
+                    ctx.request_layout();
+                } else if HotKey::new(None, KeyCode::Return).matches(key_event) {
+                    self.search_step(true);
+                } else if HotKey::new(SysMods::Shift, KeyCode::Return).matches(key_event) {
+                    self.search_step(false);
+                } else if let Some(edit_action) = self.basic.handle_event(key_event) {
+                    search.edit(edit_action);
+                }
+                ctx.request_paint();
+            },
+            Event::KeyDown(key_event) if HotKey::new(SysMods::Cmd, KeyCode::Space).matches(key_event) => {
+                let data = self.data();
+                let (token, _) = data.cursor_point();
+                self.completion = Completion::new(data.language, &data.tokens, token);
+                ctx.request_paint();
+            },
+            Event::MouseDown(mouse) if self.problem_rects.iter().any(|r| r.0.contains(mouse.pos)) => {
+                let data = self.data.as_mut().unwrap();
+                // the rects are from the last paint, the diagnostics may have changed since
+                let diagnostic = self.problem_rects.iter().find(|r| r.0.contains(mouse.pos)).and_then(|r| data.diagnostics.get(r.1));
+                if let Some(d) = diagnostic {
+                    let index = d.range.start.min(data.tokens.len() - 1);
+                    data.set_cursor(index, 0);
+                    ctx.request_paint();
+                }
+            },
+            Event::MouseMove(mouse) => {
+                let data = self.data.as_ref().unwrap();
+                let hover = token_at(&self.layout, mouse.pos)
+                    .filter(|t| !t.is_missing() && !t.is_fold())
+                    .and_then(|t| data.diagnostics.iter().position(|d| d.contains(t.index())))
+                    .map(|d| (mouse.pos, d));
+                if hover.is_some() || self.hover.is_some() {
+                    self.hover = hover;
+                    ctx.request_paint();
+                }
+            },
+            Event::KeyDown(key_event) if HotKey::new(None, KeyCode::F8).matches(key_event) => {
+                self.data.as_mut().unwrap().jump_to_diagnostic(true);
+                ctx.request_paint();
+            },
+            Event::KeyDown(key_event) if HotKey::new(SysMods::Shift, KeyCode::F8).matches(key_event) => {
+                self.data.as_mut().unwrap().jump_to_diagnostic(false);
+                ctx.request_paint();
+            },
+            Event::KeyDown(key_event) if HotKey::new(SysMods::CmdShift, KeyCode::KeyM).matches(key_event) => {
+                self.show_problems = !self.show_problems;
+                ctx.request_paint();
+            },
+            Event::MouseDown(mouse) if mouse.pos.x < GUTTER => {
+                let start = line_at(&self.layout, mouse.pos.y).and_then(|l| self.fold_marker(&self.layout[l]));
+                if let Some(start) = start {
+                    self.data.as_mut().unwrap().toggle_fold(start);
+                    ctx.request_paint();
+                    ctx.request_layout();
+                }
+            },
+            Event::KeyDown(key_event) if HotKey::new(SysMods::CmdShift, KeyCode::BracketLeft).matches(key_event) => {
+                self.data.as_mut().unwrap().fold_at_cursor();
+                ctx.request_paint();
+                ctx.request_layout();
+            },
+            Event::KeyDown(key_event) if HotKey::new(SysMods::CmdShift, KeyCode::BracketRight).matches(key_event) => {
+                self.data.as_mut().unwrap().unfold_at_cursor();
+                ctx.request_paint();
+                ctx.request_layout();
+            },
+            Event::KeyDown(key_event) if HotKey::new(SysMods::AltCmd, KeyCode::BracketLeft).matches(key_event) => {
+                self.data.as_mut().unwrap().fold_all();
+                ctx.request_paint();
+                ctx.request_layout();
+            },
+            Event::KeyDown(key_event) if HotKey::new(SysMods::AltCmd, KeyCode::BracketRight).matches(key_event) => {
+                self.data.as_mut().unwrap().unfold_all();
+                ctx.request_paint();
+                ctx.request_layout();
+            },
+            Event::MouseDown(mouse) => {
+                self.completion = None;
+                if let Some(hit) = hit_test(&self.layout, mouse.pos) {
+                    self.data.as_mut().unwrap().click(hit);
+                    ctx.request_paint();
+                    ctx.request_layout();
+                }
+            },
+            Event::KeyDown(key_event) if HotKey::new(SysMods::AltCmd, KeyCode::ArrowUp).matches(key_event) => {
+                self.add_cursor_vertical(false);
+                ctx.request_paint();
+            },
+            Event::KeyDown(key_event) if HotKey::new(SysMods::AltCmd, KeyCode::ArrowDown).matches(key_event) => {
+                self.add_cursor_vertical(true);
+                ctx.request_paint();
+            },
+            Event::KeyDown(key_event) if HotKey::new(SysMods::Cmd, KeyCode::KeyD).matches(key_event) => {
+                self.data.as_mut().unwrap().add_cursor_at_next_occurrence();
+                ctx.request_paint();
+            },
+            Event::KeyDown(key_event) if HotKey::new(None, KeyCode::Escape).matches(key_event) => {
+                self.data.as_mut().unwrap().clear_cursors();
+                ctx.request_paint();
+            },
+            Event::KeyDown(key_event) if HotKey::new(None, KeyCode::ArrowUp).matches(key_event) => {
+                self.move_vertical(false);
+                ctx.request_paint();
+            },
+            Event::KeyDown(key_event) if HotKey::new(None, KeyCode::ArrowDown).matches(key_event) => {
+                self.move_vertical(true);
+                ctx.request_paint();
+            },
+            Event::KeyDown(key_event) if HotKey::new(None, KeyCode::Tab).matches(key_event) => {
+                self.data.as_mut().unwrap().jump_to_hole(true);
+                ctx.request_paint();
+            },
+            Event::KeyDown(key_event) if HotKey::new(SysMods::Shift, KeyCode::Tab).matches(key_event) => {
+                self.data.as_mut().unwrap().jump_to_hole(false);
+                ctx.request_paint();
+            },
+            Event::KeyDown(key_event) if HotKey::new(None, KeyCode::Return).matches(key_event) => {
+                let data = self.data.as_mut().unwrap();
+                let (token, _) = data.cursor_point();
+                if data.language.node(data.tokens[token].tp).as_token().can_newline() {
+                    data.do_edit_action(EditAction::Insert(String::from("\n")));
+                    ctx.request_paint();
+                    ctx.request_layout();
+                }
+            },
             Event::KeyDown(key_event) => {
                 let edit_action = self.basic.handle_event(key_event);
                 if let Some(edit_action) = edit_action {
                     self.data.as_mut().unwrap().do_edit_action(edit_action);
+                    self.completion = self.data().completion();
                     ctx.request_paint();
                     ctx.request_layout();
                 }
@@ -374,6 +431,12 @@ impl Widget<u64> for EditorWidget {
         if !ctx.has_focus() {
             ctx.request_focus();
         }
+        self.refresh_search();
+        // the caret is shown right away after an edit or a move, the old timer is ignored when it fires
+        if let Event::KeyDown(_) | Event::MouseDown(_) = event {
+            self.caret_visible = true;
+            self.blink_timer = ctx.request_timer(BLINK);
+        }
 
         *data = self.data.as_ref().unwrap().version;
     }
@@ -384,70 +447,201 @@ impl Widget<u64> for EditorWidget {
     fn layout(&mut self, ctx: &mut LayoutCtx, bc: &BoxConstraints, _: &u64, env: &Env) -> Size {
         let mut text = ctx.text();
         if self.font.is_none() {
-            self.font = Some(text.new_font_by_name("JetBrains Mono", 14.0).build().unwrap());
+            let font = &self.theme.font;
+            self.font = Some(text.new_font_by_name(&font.family, font.size).build().unwrap());
         }
         let width = bc.max().width;
-        let text = ctx.text();
         let data = self.data();
-        self.layout = LayoutParams {
-            tokens: &data.tokens,
-            language: &data.language,
-            widget: self,
-            ctx: text, indent: 12.0
-        }.layout(&data.tree, width);
+        let (layout, foldable) = layout_document(data, ctx.text(), self.font.as_ref().unwrap(), width);
+        let folded = data.folded();
+        let delimiters: Vec<(usize, usize)> = foldable.iter()
+            .filter(|s| !folded.contains(s))
+            .filter_map(|&s| fold_range(data.language, &data.tree, s).map(|r| (s, r.end)))
+            .collect();
+        self.guides = guides(&layout, &delimiters);
+        self.layout = layout;
+        self.foldable = foldable;
         self.max_width = width;
         bc.max()
     }
 
     fn paint(&mut self, ctx: &mut PaintCtx, _: &u64, env: &Env) {
+        let region = ctx.region().to_rect();
+        ctx.fill(region, &self.theme.background);
         let layout = &self.layout;
         let data = self.data();
-        let cursor = match data.cursor {
-            Cursor::Point { token, selection } => (token, selection.end),
-        };
-        let mut top = 0.0;
-        let mut token_pos: usize = 0;
-        for line in layout {
-            let mut left = line.indent();
-            let tokens = line.tokens();
-            let height = line.ascent() + line.descent();
-            for token in tokens {
-                left += token.0;
-                let token = &token.1;
-                // draw cursor
+        let cursors: Vec<&Cursor> = std::iter::once(&data.cursor).chain(data.cursors.iter()).collect();
+        // an empty token is shown as a box, a cursor in it fills the box instead of drawing a line
+        let is_empty_token = |index: usize| data.tokens.get(index).map_or(false, |t| t.str.is_empty() && !t.hole);
+        let is_slot = |t: &TokenLayout| !t.is_missing() && !t.is_fold() && !t.has_preedit() && is_empty_token(t.index());
+        let carets: Vec<(usize, f64, bool)> = cursors.iter().filter_map(|c| match c {
+            Cursor::Point { token, selection } => cursor_position(layout, *token, selection.end).map(|(l, x)| (l, x, is_empty_token(*token)))
+        }).collect();
+        let tops = line_tops(layout);
+        // the guide of the innermost tree node around the cursor
+        let enclosing = enclosing_trees(data.language, &data.tree, data.cursor_point().0);
+        // the innermost tree node around the cursor gets a background, and its delimiters are highlighted
+        let innermost = enclosing.first().map(|n| n.start_byte()..n.end_byte());
+        let current_guide = enclosing.iter()
+            .map(|n| n.start_byte())
+            .find(|&s| self.guides.iter().any(|g| g.start == s));
+        for guide in &self.guides {
+            let color = if Some(guide.start) == current_guide { &self.theme.guide_active } else { &self.theme.guide };
+            let x = guide.x + 0.5;
+            ctx.fill(Rect::new(x, tops[guide.lines.start], x + 1.0, tops[guide.lines.end]), color);
+        }
+        let cursor_line = carets.first().map(|c| c.0);
+        let folded = data.folded();
+        for (i, line) in layout.iter().enumerate() {
+            let top = tops[i];
+            // only the lines in the painted region
+            if top + line.height() < region.y0 {
+                continue;
+            } else if top > region.y1 {
+                break;
+            }
+            let number = ctx.text().new_text_layout(self.font.as_ref().unwrap(), &(i + 1).to_string(), f64::MAX).build().unwrap();
+            let color = if cursor_line == Some(i) { &self.theme.line_number_active } else { &self.theme.line_number };
+            ctx.draw_text(&number, Point::new(FOLD_MARKER_X - 6.0 - number.width(), top + line.ascent()), color);
+            if let Some(start) = self.fold_marker(line) {
+                let marker = if folded.contains(&start) { "▸" } else { "▾" };
+                let text = ctx.text().new_text_layout(self.font.as_ref().unwrap(), marker, f64::MAX).build().unwrap();
+                ctx.draw_text(&text, Point::new(FOLD_MARKER_X, top + line.ascent()), &self.theme.line_number);
+            }
+            if let Some(range) = &innermost {
+                let mut span: Option<(f64, f64)> = None;
+                for (x, t) in line.positions().filter(|(_, t)| range.contains(&t.index())) {
+                    span = Some(span.map_or((x, x + t.width()), |(x0, x1)| (x0.min(x), x1.max(x + t.width()))));
+                }
+                if let Some((x0, x1)) = span {
+                    ctx.fill(Rect::new(x0, top, x1, top + line.height()), &self.theme.enclosing_node);
+                }
+            }
+            for (left, token) in line.positions() {
+                let rect = Rect::new(left, top, left + token.width(), top + line.height());
+                let is_delimiter = innermost.as_ref().map_or(false, |r| token.index() == r.start || token.index() + 1 == r.end);
+                if is_delimiter && !token.is_missing() && !token.is_fold() {
+                    ctx.fill(rect, &self.theme.matching_delimiter);
+                }
+                if token.is_error() {
+                    ctx.fill(rect, &self.theme.error_background);
+                }
+                if token.is_hole() || token.is_fold() {
+                    ctx.fill(rect, &self.theme.hole_background);
+                }
+                if let Some(search) = &self.search {
+                    if !token.is_missing() && search.in_tree_match(token.index()) {
+                        let current = search.tree_matches.get(search.current).map_or(false, |m| m.range.contains(&token.index()));
+                        let color = if current { &self.theme.tree_match_current } else { &self.theme.tree_match };
+                        ctx.fill(rect, color);
+                    }
+                    for (j, m) in search.matches_in(token.index()) {
+                        if let Some((x0, x1)) = token.range_x(m.token, m.range.start, m.range.end) {
+                            let color = if j == search.current { &self.theme.search_match_current } else { &self.theme.search_match };
+                            ctx.fill(Rect::new(left + x0, top, left + x1, top + line.height()), color);
+                        }
+                    }
+                }
+                for c in &cursors {
+                    let Cursor::Point { token: index, selection } = c;
+                    if let Some((x0, x1)) = token.range_x(*index, selection.min(), selection.max()) {
+                        ctx.fill(Rect::new(left + x0, top, left + x1, top + line.height()), &self.theme.selection);
+                    }
+                }
                 let text_pos = Point::new(left, top + line.ascent());
-                let layout = token.layout();
-                // TODO fat cursor for empty token
-                if token_pos == cursor.0 {
-                    let cursor_pos = if token.is_empty() {
-                        0.0
+                let color = if token.is_missing() || token.is_hole() || token.is_fold() {
+                    self.theme.placeholder.clone()
+                } else {
+                    let spec = data.language.node(token.tp()).as_token();
+                    match token.nesting() {
+                        _ if !spec.is_delimiter() || self.theme.rainbow.is_empty() => self.theme.token_color(spec, token.category()),
+                        Some(n) => self.theme.rainbow[n.saturating_sub(1) % self.theme.rainbow.len()].clone(),
+                        None => self.theme.error.clone()
+                    }
+                };
+                if is_slot(token) {
+                    let slot = Rect::new(left + 1.0, top + 2.0, left + token.width() - 1.0, top + line.height() - 2.0);
+                    if self.caret_visible && cursors.iter().any(|c| match c { Cursor::Point { token: t, .. } => *t == token.index() }) {
+                        ctx.fill(slot, &self.theme.cursor);
                     } else {
-                        let pos: Point = layout.hit_test_text_position(cursor.1).unwrap().point;
-                        pos.x
-                    };
-                    let x0 = cursor_pos + text_pos.x;
-                    let y = text_pos.y;
-                    let rect = Rect {
-                        x0,
-                        x1: x0 + 1.0,
-                        y0: y - line.ascent(),
-                        y1: y + line.descent()
-                    };
-                    ctx.fill(rect, &Color::grey8(255));
-                }
-                // let rect = Rect {
-                //     x0: left,
-                //     x1: left + layout.width(),
-                //     y0: top,
-                //     y1: top + line.descent() + line.ascent()
-                // };
-                // ctx.fill(rect, &Color::grey8(255));
-                ctx.draw_text(layout, text_pos, &style(&data.language.node(token.tp()).as_token()));
-                let width = token.width();
-                left += width;
-                token_pos += 1;
+                        ctx.stroke(slot, &self.theme.slot, 1.0);
+                    }
+                } else {
+                    ctx.draw_text(token.layout(), text_pos, &color);
+                }
+                if let Some((x0, x1)) = token.preedit_x() {
+                    let y = top + line.height() - 1.5;
+                    ctx.fill(Rect::new(left + x0, y, left + x1, y + 1.0), &self.theme.foreground);
+                }
+                if let Some(severity) = data.severities.at(token.index()).filter(|_| !token.is_fold()) {
+                    let y = top + line.height() - 1.0;
+                    ctx.stroke(squiggle(left, left + token.width().max(4.0), y), &severity_color(&self.theme, severity), 1.0);
+                }
+            }
+            for &(l, x, slot) in &carets {
+                if l == i && !slot && self.caret_visible {
+                    ctx.fill(Rect::new(x, top, x + 1.0, top + line.height()), &self.theme.cursor);
+                }
+            }
+        }
+        let font = self.font.as_ref().unwrap();
+        if let Some((pos, d)) = self.hover {
+            if let Some(d) = data.diagnostics.get(d) {
+                let text = ctx.text().new_text_layout(font, &d.message, f64::MAX).build().unwrap();
+                let rect = Rect::new(pos.x + 8.0, pos.y + 16.0, pos.x + 16.0 + text.width(), pos.y + 38.0);
+                ctx.fill(rect, &self.theme.panel);
+                ctx.stroke(rect, &severity_color(&self.theme, d.severity), 1.0);
+                ctx.draw_text(&text, Point::new(rect.x0 + 4.0, rect.y1 - 6.0), &self.theme.foreground);
+            }
+        }
+        if let Some(completion) = &self.completion {
+            if let Some((line, x)) = cursor_position(layout, completion.token, 0) {
+                let y = tops[line + 1];
+                let labels: Vec<_> = completion.candidates.iter().take(10).map(|&tp| {
+                    ctx.text().new_text_layout(font, &candidate_label(data.language, tp), f64::MAX).build().unwrap()
+                }).collect();
+                let width = labels.iter().map(|l| l.width()).fold(0.0, f64::max) + 16.0;
+                for (i, label) in labels.iter().enumerate() {
+                    let rect = Rect::new(x, y + 20.0 * i as f64, x + width, y + 20.0 * (i + 1) as f64);
+                    ctx.fill(rect, if i == completion.selected { &self.theme.selection } else { &self.theme.panel });
+                    ctx.draw_text(label, Point::new(rect.x0 + 8.0, rect.y1 - 5.0), &self.theme.foreground);
+                }
+            }
+        }
+        let mut problem_rects = vec![];
+        if self.show_problems {
+            let region = ctx.region().to_rect();
+            let count = data.diagnostics.len().min(8);
+            let panel = Rect::new(region.x0, region.y1 - 24.0 - 20.0 * count as f64, region.x1, region.y1);
+            ctx.fill(panel, &self.theme.panel);
+            let title = format!("Problems ({})", data.diagnostics.len());
+            let text = ctx.text().new_text_layout(font, &title, f64::MAX).build().unwrap();
+            ctx.draw_text(&text, Point::new(panel.x0 + 8.0, panel.y0 + 18.0), &self.theme.foreground);
+            for (i, d) in data.diagnostics.iter().take(count).enumerate() {
+                let y0 = panel.y0 + 24.0 + 20.0 * i as f64;
+                let line = cursor_position(layout, d.range.start, 0).map_or(0, |n| n.0) + 1;
+                let entry = format!("{}: {}", line, d.message);
+                let text = ctx.text().new_text_layout(font, &entry, f64::MAX).build().unwrap();
+                ctx.draw_text(&text, Point::new(panel.x0 + 8.0, y0 + 15.0), &severity_color(&self.theme, d.severity));
+                problem_rects.push((Rect::new(panel.x0, y0, panel.x1, y0 + 20.0), i));
+            }
+        }
+        self.problem_rects = problem_rects;
+        if let Some(search) = &self.search {
+            let region = ctx.region().to_rect();
+            let rows = [
+                format!("{} {}", if search.focus == SearchField::Query { "▸ find   " } else { "  find   " }, search.query.text),
+                format!("{} {}", if search.focus == SearchField::Replace { "▸ replace" } else { "  replace" }, search.replace),
+                search.status(data.language)
+            ];
+            let texts: Vec<_> = rows.iter().map(|r| ctx.text().new_text_layout(font, r, f64::MAX).build().unwrap()).collect();
+            let width = texts.iter().map(|t| t.width()).fold(240.0, f64::max) + 16.0;
+            let panel = Rect::new(region.x1 - width - 8.0, region.y0 + 8.0, region.x1 - 8.0, region.y0 + 8.0 + 20.0 * rows.len() as f64 + 8.0);
+            ctx.fill(panel, &self.theme.panel);
+            for (i, text) in texts.iter().enumerate() {
+                let color = if i == 2 && search.error.is_some() { &self.theme.error } else { &self.theme.foreground };
+                ctx.draw_text(text, Point::new(panel.x0 + 8.0, panel.y0 + 20.0 * (i + 1) as f64), color);
             }
-            top += height;
         }
     }
 }
@@ -457,60 +651,247 @@ impl Widget<u64> for EditorWidget {
 
 
 
+/// the delimiter of the other side is missing
+fn has_missing_delimiter(tree: Node) -> bool {
+    let count = tree.child_count();
+    count == 0 || tree.child(0).map_or(false, |c| c.is_missing()) || tree.child(count - 1).map_or(false, |c| c.is_missing())
+}
+
 // TODO maybe font caching should be done somewhere, so widget is not a parameter anymore
 struct LayoutParams<'a, 'b, 'c> {
     tokens: &'a Tokens,
     language: &'static Language,
-    widget: &'b EditorWidget,
+    /// see `EditorState::folded`
+    folds: BTreeSet<usize>,
+    font: &'b PietFont,
     ctx: PietText<'c>,
-    indent: f64,
+    /// how many ERROR nodes we are in
+    errors: usize,
+    /// how many tree nodes we are in
+    trees: usize,
+    /// see `EditorState::composition`
+    composition: Option<(usize, usize, &'a str)>,
+    /// see `EditorWidget::foldable`
+    foldable: BTreeSet<usize>,
+}
+
+/// the lines of the whole document and the foldable tree nodes, the gutter is included
+pub fn layout_document(data: &EditorState, text: PietText, font: &PietFont, width: f64) -> (Vec<Line>, BTreeSet<usize>) {
+    let mut params = LayoutParams {
+        tokens: &data.tokens,
+        language: &data.language,
+        folds: data.folded(),
+        font,
+        ctx: text,
+        errors: 0,
+        trees: 0,
+        composition: data.composition(),
+        foldable: BTreeSet::new()
+    };
+    let layout = params.layout(&data.tree, width);
+    (layout, params.foldable)
 }
 
 impl LayoutParams<'_, '_, '_> {
-    fn layout_token(&mut self, node: Node, tp: &TokenSpec) -> LayoutResult {
-        let token = self.tokens[node.start_byte()].clone();
-        let layout = self.ctx.new_text_layout(
-            self.widget.font.as_ref().unwrap(),
-            if token.str.is_empty() { "*" } else { &token.str },
-            f64::MAX,
-        ).build().unwrap();
+    fn text_layout(&mut self, text: &str, width: f64) -> PietTextLayout {
+        self.ctx.new_text_layout(self.font, text, width).build().unwrap()
+    }
+
+    /// the longest prefix of `text` fits in `max_width`, at a word boundary if possible, or else a grapheme boundary.
+    /// only a bit more than a row is laid out, more when it all fits, so a long line costs about a layout per row
+    fn wrap_point(&mut self, text: &str, max_width: f64) -> usize {
+        if text.is_empty() {
+            return 0;
+        }
+        let char_width = self.text_layout("0", f64::MAX).width().max(1.0);
+        let mut chars = (max_width / char_width) as usize * 2 + 16;
+        let (text, layout, len) = loop {
+            let prefix = &text[..text.char_indices().nth(chars).map_or(text.len(), |c| c.0)];
+            let layout = self.text_layout(prefix, max_width);
+            let len = layout.line_metric(0).map_or(prefix.len(), |m| m.end_offset);
+            if len < prefix.len() || prefix.len() == text.len() {
+                break (prefix, layout, len);
+            }
+            chars *= 2;
+        };
+        if len > 0 && self.text_layout(&text[..len], f64::MAX).width() <= max_width {
+            return len;
+        }
+        // a single word that is too long
+        let pos = layout.hit_test_point(Point::new(max_width, 0.0)).metrics.text_position;
+        let mut boundary = 0;
+        for (i, _) in text.grapheme_indices(true) {
+            if i > pos {
+                break;
+            }
+            boundary = i;
+        }
+        if boundary == 0 {
+            boundary = text.grapheme_indices(true).nth(1).map_or(text.len(), |n| n.0);
+        }
+        boundary
+    }
+
+    fn layout_token(&mut self, node: Node, tp: &TokenSpec, max_width_first: f64, max_width_remaining: f64) -> LayoutResult {
+        let index = node.start_byte();
+        let tokens = self.tokens;
+        let token = &tokens[index];
         let is_sep = tp.is_separator();
-        let margin = if is_sep { 2.0 } else { 8.0 };
-        LayoutResult::Single(TokenLayout::new(
-            token,
-            margin,
-            margin,
-            is_sep,
-            layout
-        ))
+        let margin = self.language.style().margin(tp);
+        // every fragment after the first starts a new line, either because of a newline character or wrapping. a large
+        // token is read line by line, and only a fragment with the pre-edit text is read again
+        let mut fragments: Vec<(usize, usize, Option<Shaped>)> = vec![];
+        for (start, line) in token.str.lines() {
+            let max_width = if fragments.is_empty() { max_width_first } else { max_width_remaining };
+            let mut pieces = vec![];
+            if tp.can_wrap() {
+                let mut at = 0;
+                let mut max_width = max_width;
+                loop {
+                    let wrap = at + self.wrap_point(&line[at..], max_width.max(1.0));
+                    pieces.push((at, wrap));
+                    at = wrap;
+                    max_width = max_width_remaining;
+                    if at >= line.len() {
+                        break;
+                    }
+                }
+            } else {
+                pieces.push((0, line.len()));
+            }
+            for (s, e) in pieces {
+                let shaped = if s < e { Some(shape(&line[s..e], tp.shaping(), None)) } else { None };
+                fragments.push((start + s, start + e, shaped));
+            }
+        }
+        let category = self.language.category(token.tp, || Some(node));
+        let nesting = if tp.is_delimiter() && self.errors == 0 && node.parent().map_or(false, |p| !has_missing_delimiter(p)) {
+            Some(self.trees)
+        } else {
+            None
+        };
+        let hole = format!("⟨{}⟩", tp.name());
+        // the pre-edit text is in the last fragment containing the cursor, where the cursor is shown
+        let composition = self.composition.filter(|c| c.0 == index && !token.hole);
+        let preedit_fragment = composition.and_then(|(_, offset, _)| fragments.iter().rposition(|&(s, e, _)| s <= offset && offset <= e));
+        let empty = token.str.is_empty();
+        let mut layouts: Vec<TokenLayout> = fragments.into_iter().enumerate().map(|(i, (start, end, shaped))| {
+            let preedit = composition.filter(|_| preedit_fragment == Some(i)).map(|(_, offset, p)| (offset - start, p));
+            let shaped = if token.hole {
+                Shaped { text: hole.clone(), map: vec![], preedit: None }
+            } else if let Some(preedit) = preedit {
+                shape(&token.str.slice(start..end), tp.shaping(), Some(preedit))
+            } else if empty {
+                Shaped { text: String::from("  "), map: vec![], preedit: None } // painted as a slot box
+            } else if let Some(shaped) = shaped {
+                shaped
+            } else {
+                Shaped { text: String::from(" "), map: vec![], preedit: None } // empty line inside a token still have a line height
+            };
+            let layout = self.text_layout(&shaped.text, f64::MAX);
+            let flags = TokenFlags { is_separator: is_sep, error: self.errors > 0, hole: token.hole, ..TokenFlags::default() };
+            TokenLayout::new(index, token.tp, category, start..end, margin, flags, layout)
+                .with_nesting(nesting)
+                .with_shaped(shaped.map, shaped.preedit)
+        }).collect();
+        if layouts.len() == 1 {
+            LayoutResult::Single(layouts.remove(0))
+        } else {
+            let mut block = Block::new();
+            for (i, layout) in layouts.into_iter().enumerate() {
+                if i > 0 {
+                    block.nl(0.0);
+                }
+                block.append(LayoutResult::Single(layout));
+            }
+            LayoutResult::Block(block)
+        }
     }
 
     fn layout(&mut self, tree: &Tree, max_width: f64) -> Vec<Line> {
-        self.layout_node(tree.root_node(), 0, max_width, max_width).to_lines()
+        let width = max_width - GUTTER;
+        let mut block = self.layout_node(tree.root_node(), 0, width, width).to_block();
+        block.indent(GUTTER);
+        LayoutResult::Block(block).to_lines()
+    }
+
+    /// `{ … 3 items }`, the delimiters are still tokens
+    fn layout_folded(&mut self, node: Node, depth: i32) -> LayoutResult {
+        let mut block = Block::new();
+        let first = self.layout_node(node.child(0).unwrap(), depth + 1, f64::MAX, f64::MAX);
+        block.append(first);
+        let count = element_count(self.language, node);
+        let text = if count == 1 { String::from("… 1 item") } else { format!("… {} items", count) };
+        let margin = self.language.style().keyword.clone();
+        let layout = self.text_layout(&text, f64::MAX);
+        block.append(LayoutResult::Single(TokenLayout::fold(node.start_byte(), margin.left, margin.right, layout)));
+        let last = self.layout_node(node.child(node.child_count() - 1).unwrap(), depth + 1, f64::MAX, f64::MAX);
+        block.append(last);
+        block.wrap()
+    }
+
+    /// a missing node is zero width, so it is not in the tokens, we show a placeholder of what is expected
+    fn layout_missing(&mut self, node: Node) -> LayoutResult {
+        let tp = node.kind_id();
+        let spec = self.language.node(tp);
+        let (text, margin, is_sep, category) = match spec {
+            NodeSpec::Token(t @ TokenSpec::Constant { .. }) => (String::from(t.name()), self.language.style().margin(t), t.is_separator(), t.category()),
+            NodeSpec::Token(t) => (format!("⟨{}⟩", t.name()), self.language.style().margin(t), false, t.category()),
+            _ => (String::from("⟨?⟩"), &self.language.style().regex, false, "")
+        };
+        let layout = self.text_layout(&text, f64::MAX);
+        let flags = TokenFlags { is_separator: is_sep, error: self.errors > 0, missing: true, ..TokenFlags::default() };
+        LayoutResult::Single(TokenLayout::new(node.start_byte(), tp, category, 0..0, margin, flags, layout))
     }
 
     fn layout_node(&mut self, node: Node, depth: i32, max_width_first: f64, max_width_remaining: f64) -> LayoutResult {
-        let error = node.is_error(); // TODO handle this
+        if node.is_missing() {
+            return self.layout_missing(node);
+        }
+        // error nodes are laid out like a compose node, with the tokens marked, extras are laid out inline
+        let error = node.is_error();
+        let tree = self.language.node(node.kind_id()).delimiters().is_some();
+        if error {
+            self.errors += 1;
+        }
+        if tree {
+            self.trees += 1;
+        }
+        let res = self.layout_node_spec(node, depth, max_width_first, max_width_remaining);
+        if error {
+            self.errors -= 1;
+        }
+        if tree {
+            self.trees -= 1;
+        }
+        res
+    }
+
+    fn layout_node_spec(&mut self, node: Node, depth: i32, max_width_first: f64, max_width_remaining: f64) -> LayoutResult {
         let nt = node.kind_id();
         // println!("layouting node {}, {}, with {}, {}", nt, depth, max_width_first, max_width_remaining);
-        if node.is_missing() { panic!("missing node with type {}", nt) }; // we don't know what to do yet.
-        if node.is_extra() && nt != 65535 { panic!("extra node is not handled {}", nt) }; // we don't know what to do yet.
-        // TODO handle extra nodes
         match &self.language.node(nt) {
+            NodeSpec::Tree { .. } | NodeSpec::Positional { .. } if self.folds.contains(&node.start_byte()) && !node.has_error() => {
+                self.foldable.insert(node.start_byte());
+                self.layout_folded(node, depth)
+            },
             NodeSpec::Tree { start, sep, end } => {
+                let style = self.language.style();
+                let indent = style.indent;
+                let hint = style.break_hint(nt);
                 let mut cursor = node.walk();
                 let mut children_layout: Vec<(u16, LayoutResult)> = vec![];
                 let mut has_child = cursor.goto_first_child();
-                let mut is_block = false;
+                let mut is_block = hint == BreakHint::Always;
                 let mut current_width = 0.0;
                 while has_child {
                     let node = cursor.node();
                     let kind = node.kind_id();
                     let (c_first, c_remaining) = if is_block {
-                        let width = max_width_remaining - self.indent;
+                        let width = max_width_remaining - indent;
                         (width, width)
                     } else {
-                        (max_width_first - current_width, max_width_remaining - self.indent)
+                        (max_width_first - current_width, max_width_remaining - indent)
                     };
 
                     let mut layout = self.layout_node(node, depth + 1, c_first, c_remaining);
@@ -519,7 +900,7 @@ impl LayoutParams<'_, '_, '_> {
                             is_block = true;
                             // LATER it is possible first item is not a single line after indent is added
                             // TODO this causes massive relayout sometimes?
-                            let width = max_width_remaining - self.indent;
+                            let width = max_width_remaining - indent;
                             self.layout_node(node,  depth + 1, width, width)
                         }
                         mut ll => {
@@ -533,13 +914,13 @@ impl LayoutParams<'_, '_, '_> {
                                     _ => panic!()
                                 });
                                 let t2 = match ll {
-                                    LayoutResult::Line(l) => l.last().unwrap(),
-                                    LayoutResult::Single(s) => s,
+                                    LayoutResult::Line(l) => l.first(),
+                                    LayoutResult::Single(s) => Some(&*s),
                                     _ => panic!()
                                 };
-                                let added_width = layout_width + Line::merge_margin(t1, t2);
+                                let added_width = layout_width + t2.map_or(0.0, |t2| Line::merge_margin(t1, t2));
                                 current_width += added_width;
-                                is_block = c_first < added_width;
+                                is_block = hint != BreakHint::Never && c_first < added_width;
                                 // if is_block {
                                 //     println!("turned into block, {}, {}", c_first, current_width);
                                 // }
@@ -551,6 +932,7 @@ impl LayoutParams<'_, '_, '_> {
                     has_child = cursor.goto_next_sibling();
                 }
                 if is_block {
+                    self.foldable.insert(node.start_byte());
                     let mut block = Block::new();
                     let mut inside = false;
                     for (role, child) in children_layout {
@@ -559,15 +941,17 @@ impl LayoutParams<'_, '_, '_> {
                             inside = true;
                         } else if end.contains(&role) {
                             inside = false;
-                            block.nl(0.0);
+                            if style.end_delimiter == EndDelimiter::Align {
+                                block.nl(0.0);
+                            }
                             block.append(child);
                         } else if sep.contains(&role) {
                             block.append(child);
                         } else {
-                            block.nl(self.indent);
+                            block.nl(indent);
                             let mut bl = child.to_block();
                             if inside {
-                                bl.indent(self.indent);
+                                bl.indent(indent);
                             }
                             block.append_block(bl);
                         }
@@ -589,8 +973,66 @@ impl LayoutParams<'_, '_, '_> {
                     LayoutResult::Line(line)
                 }
             },
+            NodeSpec::Positional { start, head, align, end } => {
+                let style = self.language.style();
+                let hint = style.break_hint(nt);
+                let mut children: Vec<Node> = vec![];
+                let mut cursor = node.walk();
+                let mut has_child = cursor.goto_first_child();
+                while has_child {
+                    children.push(cursor.node());
+                    has_child = cursor.goto_next_sibling();
+                }
+                if hint != BreakHint::Always {
+                    let mut line = Line::new();
+                    let mut fits = true;
+                    for &child in &children {
+                        match self.layout_node(child, depth + 1, f64::MAX, f64::MAX) {
+                            LayoutResult::Single(a) => line.push(a),
+                            LayoutResult::Line(l) => line.append(l),
+                            LayoutResult::Block(_) => {
+                                fits = false;
+                                break;
+                            }
+                        }
+                    }
+                    if fits && (hint == BreakHint::Never || line.width() <= max_width_first) {
+                        return LayoutResult::Line(line);
+                    }
+                }
+                let mut block = Block::new();
+                let mut column = style.indent;
+                for (i, &child) in children.iter().enumerate() {
+                    if i < *head {
+                        let max_width = if block.single_line() { max_width_first } else { max_width_remaining };
+                        let mut layout = self.layout_node(child, depth + 1, max_width - block.last_width(), max_width_remaining);
+                        let width = layout.width();
+                        block.append(layout);
+                        if *align && i + 1 == *head {
+                            column = block.last_width() - width;
+                        }
+                    } else if end.contains(&child.kind_id()) {
+                        let width = max_width_remaining - block.last_width();
+                        let layout = self.layout_node(child, depth + 1, width, max_width_remaining);
+                        if style.end_delimiter == EndDelimiter::Align {
+                            block.nl(0.0);
+                        }
+                        block.append(layout);
+                    } else {
+                        let width = max_width_remaining - column;
+                        let mut bl = self.layout_node(child, depth + 1, width, width).to_block();
+                        block.nl(column);
+                        bl.indent(column);
+                        block.append_block(bl);
+                    }
+                }
+                if !start.is_empty() {
+                    self.foldable.insert(node.start_byte());
+                }
+                LayoutResult::Block(block)
+            },
             NodeSpec::Token(tp) => {
-                self.layout_token(node, tp)
+                self.layout_token(node, tp, max_width_first, max_width_remaining)
             },
             _ => {
                 let mut block = Block::new();
@@ -611,3 +1053,130 @@ impl LayoutParams<'_, '_, '_> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use druid::im::vector;
+    use crate::languages::json;
+
+    /// json with arrays laid out like `(list 1` + the rest
+    fn positional(align: bool) -> &'static Language {
+        let mut nodes = json::nodes();
+        nodes[17] = NodeSpec::Positional { start: vec![5], head: 2, align, end: vec![6] };
+        Box::leak(Box::new(Language::new(nodes, LayoutStyle::default(), json::rules(), vec![], vec![], json::language())))
+    }
+
+    fn layout(language: &'static Language, width: f64) -> Vec<Line> {
+        let state = EditorState::with_tokens(language, vector![
+            Token::new(5, "["),
+            Token::new(8, "1"),
+            Token::new(2, ","),
+            Token::new(8, "2"),
+            Token::new(2, ","),
+            Token::new(8, "3"),
+            Token::new(6, "]")
+        ]);
+        let mut text = PietText::new();
+        let font = text.new_font_by_name("JetBrains Mono", 14.0).build().unwrap();
+        layout_document(&state, text, &font, width).0
+    }
+
+    fn starts(lines: &[Line]) -> Vec<usize> {
+        lines.iter().map(|l| l.first().unwrap().index()).collect()
+    }
+
+    #[test]
+    fn positional_fits() {
+        let lines = layout(positional(false), 1000.0);
+        assert_eq!(starts(&lines), vec![0]);
+        assert_eq!(lines[0].len(), 7);
+    }
+
+    #[test]
+    fn positional_indented() {
+        let lines = layout(positional(false), GUTTER + 10.0);
+        // the head stays on the first line, the end delimiter is aligned with the start
+        assert_eq!(starts(&lines), vec![0, 2, 3, 4, 5, 6]);
+        assert_eq!(lines[0].len(), 2);
+        let indent = LayoutStyle::default().indent;
+        for line in &lines[1..5] {
+            assert_eq!(line.indent(), GUTTER + indent);
+        }
+        assert_eq!(lines[5].indent(), GUTTER);
+    }
+
+    #[test]
+    fn positional_aligned() {
+        let lines = layout(positional(true), GUTTER + 10.0);
+        assert_eq!(starts(&lines), vec![0, 2, 3, 4, 5, 6]);
+        // the rest start where the last head child does
+        let head = lines[0].positions().nth(1).unwrap().0;
+        for line in &lines[1..5] {
+            assert!((line.indent() - head).abs() < 0.001);
+        }
+        assert_eq!(lines[5].indent(), GUTTER);
+    }
+
+    #[test]
+    fn positional_delimiters() {
+        let language = positional(false);
+        assert_eq!(language.closing(5), Some(6));
+        assert!(language.is_end_delimiter(6));
+        let state = EditorState::with_tokens(language, vector![
+            Token::new(5, "["), Token::new(8, "1"), Token::new(2, ","), Token::new(8, "2"), Token::new(6, "]")
+        ]);
+        assert_eq!(enclosing_trees(language, &state.tree, 1).first().map(|n| n.start_byte()), Some(0));
+        let mut text = PietText::new();
+        let font = text.new_font_by_name("JetBrains Mono", 14.0).build().unwrap();
+        let (lines, foldable) = layout_document(&state, text, &font, GUTTER + 10.0);
+        assert!(foldable.contains(&0));
+        let nesting: Vec<Option<usize>> = lines.iter().flat_map(|l| l.positions().map(|p| p.1))
+            .filter(|t| t.tp() == 5 || t.tp() == 6).map(|t| t.nesting()).collect();
+        assert_eq!(nesting, vec![Some(1), Some(1)]);
+    }
+
+    #[test]
+    fn wrap_long_line() {
+        let words: Vec<String> = (0..300).map(|i| format!("w{}", i)).collect();
+        let mut string = Token::new(7, "");
+        string.str = TokenText::new(words.join(" "));
+        let state = EditorState::with_tokens(&json::INSTANCE, vector![Token::new(5, "["), string, Token::new(6, "]")]);
+        let mut text = PietText::new();
+        let font = text.new_font_by_name("JetBrains Mono", 14.0).build().unwrap();
+        let width = GUTTER + 200.0;
+        let lines = layout_document(&state, text, &font, width).0;
+        assert!(lines.len() > 10);
+        for line in &lines {
+            assert!(line.positions().all(|(x, t)| t.index() != 1 || x + t.width() <= width + 0.001));
+        }
+        // the rows cover the text, and words are not broken
+        let mut last = 0;
+        let mut offset = 0;
+        for word in &words {
+            let (start, _) = cursor_position(&lines, 1, offset).unwrap();
+            let (end, _) = cursor_position(&lines, 1, offset + word.len() - 1).unwrap();
+            assert_eq!(start, end);
+            assert!(start >= last);
+            last = start;
+            offset += word.len() + 1;
+        }
+    }
+
+    #[test]
+    fn preedit_in_cursor_fragment() {
+        let mut state = EditorState::with_tokens(&json::INSTANCE, vector![Token::new(5, "["), Token::new(7, "ab"), Token::new(6, "]")]);
+        state.set_cursor(1, 1);
+        let mut text = PietText::new();
+        let font = text.new_font_by_name("JetBrains Mono", 14.0).build().unwrap();
+        let width = layout_document(&state, PietText::new(), &font, 1000.0).0[0].width();
+        state.set_composition("xy");
+        let lines = layout_document(&state, text, &font, 1000.0).0;
+        let preedit: Vec<&TokenLayout> = lines.iter().flat_map(|l| l.positions().map(|p| p.1)).filter(|t| t.has_preedit()).collect();
+        assert_eq!(preedit.len(), 1);
+        assert_eq!(preedit[0].index(), 1);
+        let (x0, x1) = preedit[0].preedit_x().unwrap();
+        assert!(x0 > 0.0 && x1 > x0);
+        assert!(lines[0].width() > width);
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::*;
+
+// tree-sitter doesn't give us the parse table, so we compute which token can follow which from our own copy of the
+// grammar. it is an over approximation because the context is lost, but it is good enough for error messages
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Info {
+    nullable: bool,
+    first: BTreeSet<u16>,
+    last: BTreeSet<u16>
+}
+
+pub struct Expectations {
+    /// tokens can start the document
+    first: Vec<u16>,
+    /// indexed by token type, tokens can come right after it
+    follow: Vec<Vec<u16>>
+}
+
+struct Analysis<'a> {
+    nodes: &'a [NodeSpec],
+    rules: HashMap<&'static str, &'a Syntax>,
+    infos: HashMap<&'static str, Info>,
+    follow: Vec<BTreeSet<u16>>
+}
+
+impl Analysis<'_> {
+    fn token(&self, t: &TokenRef) -> u16 {
+        self.nodes.iter().position(|n| match (n, t) {
+            (NodeSpec::Token(TokenSpec::Constant { str, .. }), TokenRef::Constant(s)) => str == s,
+            (NodeSpec::Token(TokenSpec::Regex { name, .. }), TokenRef::Regex(s)) => name == s,
+            _ => false
+        }).unwrap_or_else(|| panic!("token {:?} not found", t)) as u16
+    }
+
+    fn sequence(&self, infos: Vec<Info>) -> Info {
+        let mut res = Info { nullable: true, ..Info::default() };
+        for info in &infos {
+            if res.nullable {
+                res.first.extend(&info.first);
+            }
+            res.nullable = res.nullable && info.nullable;
+        }
+        for info in infos.iter().rev() {
+            res.last.extend(&info.last);
+            if !info.nullable {
+                break;
+            }
+        }
+        res
+    }
+
+    fn info(&self, syntax: &Syntax) -> Info {
+        match syntax {
+            Syntax::Token(t) => {
+                let t = self.token(t);
+                Info { nullable: false, first: vec![t].into_iter().collect(), last: vec![t].into_iter().collect() }
+            },
+            Syntax::Ref(r) => self.infos.get(r).cloned().unwrap_or_default(),
+            Syntax::Choice(cs) => {
+                let mut res = Info::default();
+                for c in cs {
+                    let info = self.info(c);
+                    res.nullable = res.nullable || info.nullable;
+                    res.first.extend(info.first);
+                    res.last.extend(info.last);
+                }
+                res
+            },
+            Syntax::Combine(cs) => self.sequence(cs.iter().map(|c| self.info(c)).collect()),
+            Syntax::Sep { child, sep } => {
+                let child = self.info(child);
+                let sep = self.info(sep);
+                let mut res = child.clone();
+                if child.nullable {
+                    res.first.extend(sep.first);
+                    res.last.extend(sep.last);
+                }
+                res
+            },
+            Syntax::Repeat { child, min, .. } => {
+                let mut res = self.info(child);
+                res.nullable = res.nullable || *min == 0;
+                res
+            },
+            Syntax::Tree { start, end, .. } => self.sequence(vec![self.info(start), self.info(end)])
+        }
+    }
+
+    fn adjacent(&mut self, a: &Info, b: &Info) {
+        for &t in &a.last {
+            self.follow[t as usize].extend(&b.first);
+        }
+    }
+
+    fn edges(&mut self, syntax: &Syntax) {
+        match syntax {
+            Syntax::Token(_) | Syntax::Ref(_) => {},
+            Syntax::Choice(cs) => for c in cs {
+                self.edges(c);
+            },
+            Syntax::Combine(cs) => {
+                let infos: Vec<Info> = cs.iter().map(|c| self.info(c)).collect();
+                for i in 0..infos.len() {
+                    for j in i + 1..infos.len() {
+                        self.adjacent(&infos[i], &infos[j]);
+                        if !infos[j].nullable {
+                            break;
+                        }
+                    }
+                }
+                for c in cs {
+                    self.edges(c);
+                }
+            },
+            Syntax::Sep { child, sep } => {
+                let (c, s) = (self.info(child), self.info(sep));
+                self.adjacent(&c, &s);
+                self.adjacent(&s, &c);
+                self.edges(child);
+                self.edges(sep);
+            },
+            Syntax::Repeat { child, max, .. } => {
+                if *max > 1 {
+                    let c = self.info(child);
+                    self.adjacent(&c, &c);
+                }
+                self.edges(child);
+            },
+            Syntax::Tree { start, child, sep, end } => {
+                let (st, c, s, e) = (self.info(start), self.info(child), self.info(sep), self.info(end));
+                self.adjacent(&st, &c);
+                self.adjacent(&st, &e);
+                self.adjacent(&c, &s);
+                self.adjacent(&s, &c);
+                self.adjacent(&c, &e);
+                for &n in [start, child, sep, end].iter() {
+                    self.edges(n);
+                }
+            }
+        }
+    }
+}
+
+impl Expectations {
+    /// the first rule is the document
+    pub fn new(nodes: &[NodeSpec], rules: &[Rule]) -> Expectations {
+        let mut analysis = Analysis {
+            nodes,
+            rules: rules.iter().map(|r| (r.name, &r.body)).collect(),
+            infos: HashMap::new(),
+            follow: vec![BTreeSet::new(); nodes.len()]
+        };
+        loop {
+            let infos: HashMap<&'static str, Info> = analysis.rules.iter().map(|(&n, &s)| (n, analysis.info(s))).collect();
+            if infos == analysis.infos {
+                break;
+            }
+            analysis.infos = infos;
+        }
+        let bodies: Vec<&Syntax> = rules.iter().map(|r| &r.body).collect();
+        for body in bodies {
+            analysis.edges(body);
+        }
+        let first = rules.first().map_or(vec![], |r| analysis.info(&r.body).first.into_iter().collect());
+        Expectations {
+            first,
+            follow: analysis.follow.into_iter().map(|n| n.into_iter().collect()).collect()
+        }
+    }
+
+    /// tokens can come after `token`, or at the start of the document
+    pub fn after(&self, token: Option<u16>) -> &[u16] {
+        match token {
+            None => &self.first,
+            Some(t) => self.follow.get(t as usize).map_or(&[][..], |n| n.as_slice())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::languages::json::INSTANCE as JSON;
+
+    #[test]
+    fn json_start() {
+        assert_eq!(JSON.expected_after(None), &[1, 5, 7, 8, 9, 10, 11]);
+    }
+
+    #[test]
+    fn json_after_start_delimiter() {
+        // a key or the end of an empty object
+        assert_eq!(JSON.expected_after(Some(1)), &[3, 7]);
+        assert_eq!(JSON.expected_after(Some(5)), &[1, 5, 6, 7, 8, 9, 10, 11]);
+    }
+
+    #[test]
+    fn json_after_colon() {
+        assert_eq!(JSON.expected_after(Some(4)), &[1, 5, 7, 8, 9, 10, 11]);
+    }
+
+    #[test]
+    fn json_after_value() {
+        assert_eq!(JSON.expected_after(Some(8)), &[2, 3, 6]);
+        assert_eq!(JSON.expected_after(Some(3)), &[2, 3, 6]);
+        // a string can also be a key
+        assert_eq!(JSON.expected_after(Some(7)), &[2, 3, 4, 6]);
+    }
+
+    #[test]
+    fn json_after_comma() {
+        // the context is lost, so a value is allowed after the comma of an object too
+        assert_eq!(JSON.expected_after(Some(2)), &[1, 5, 7, 8, 9, 10, 11]);
+    }
+
+    #[test]
+    fn json_after_lex_error() {
+        // not in the grammar, so nothing is expected after it
+        assert_eq!(JSON.expected_after(Some(12)), &[] as &[u16]);
+    }
+}
@@ -1,11 +1,18 @@
-use crate::{NodeSpec, TokenSpec};
+use crate::{NodeSpec, TokenSpec, LayoutStyle, Rule, Expectations, SearchScope, TokenPattern};
 use itertools::Itertools;
+use tree_sitter::Node;
 
 
 // TOKENS, nodes
 // token_type, node_type
 pub struct Language {
     nodes: Vec<NodeSpec>,
+    style: LayoutStyle,
+    expectations: Expectations,
+    /// the first one is all tokens
+    search_scopes: Vec<SearchScope>,
+    /// the first matching one overrides the category of the token spec
+    categories: Vec<(TokenPattern, &'static str)>,
     language: tree_sitter::Language,
     lex_error: u16,
     constants: Vec<u16>,
@@ -15,6 +22,22 @@ pub struct Language {
 impl Language {
     pub fn language(&self) -> tree_sitter::Language { self.language }
 
+    pub fn style(&self) -> &LayoutStyle { &self.style }
+
+    pub fn search_scopes(&self) -> &[SearchScope] { &self.search_scopes }
+
+    /// the semantic category of a token in its context, `node` is like `TokenPattern::matches`
+    pub fn category<'a>(&self, tp: u16, node: impl Fn() -> Option<Node<'a>>) -> &'static str {
+        self.categories.iter()
+            .find(|(p, _)| p.matches(tp, &node))
+            .map_or_else(|| self.node(tp).as_token().category(), |c| c.1)
+    }
+
+    /// tokens are valid after `token`, `None` is the start of the document
+    pub fn expected_after(&self, token: Option<u16>) -> &[u16] {
+        self.expectations.after(token)
+    }
+
     pub fn lex_error(&self) -> u16 {
         self.lex_error
     }
@@ -36,7 +59,25 @@ impl Language {
         None
     }
 
-    pub fn new(nodes: Vec<NodeSpec>, language: tree_sitter::Language) -> Language {
+    /// the end delimiter of the tree node which `tp` is a start delimiter of
+    pub fn closing(&self, tp: u16) -> Option<u16> {
+        self.nodes.iter().filter_map(|n| n.delimiters()).find_map(|(start, end)| {
+            start.iter().position(|&s| s == tp).and_then(|i| end.get(i).or(end.first())).cloned()
+        })
+    }
+
+    pub fn is_end_delimiter(&self, tp: u16) -> bool {
+        self.nodes.iter().filter_map(|n| n.delimiters()).any(|(_, end)| end.contains(&tp))
+    }
+
+    pub fn new(
+        nodes: Vec<NodeSpec>,
+        style: LayoutStyle,
+        rules: Vec<Rule>,
+        scopes: Vec<SearchScope>,
+        categories: Vec<(TokenPattern, &'static str)>,
+        language: tree_sitter::Language
+    ) -> Language {
         let lex_error = nodes.iter().position(|n| match n {
             NodeSpec::Token(t) => t.is_lex_error(),
             _ => false
@@ -59,7 +100,10 @@ impl Language {
         for (p, i) in &regexes.into_iter().group_by(|n| n.0) {
             grouped.push((p, i.map(|n| n.1).collect()))
         }
-        Language { nodes, language, lex_error, constants, regexes: grouped }
+        let expectations = Expectations::new(&nodes, &rules);
+        let mut search_scopes = vec![SearchScope::new("all", TokenPattern::any())];
+        search_scopes.extend(scopes);
+        Language { nodes, style, expectations, search_scopes, categories, language, lex_error, constants, regexes: grouped }
     }
     pub fn node(&self, n: u16) -> &NodeSpec {
         if n == 65535 {
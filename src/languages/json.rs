@@ -10,71 +10,125 @@ lazy_static! {
 
 fn create() -> crate::Language {
     crate::Language::new(
+        nodes(),
+        LayoutStyle::default(),
+        rules(),
         vec![
-            unused_node_spec(), // 0
-            NodeSpec::Token(TokenSpec::delimiter("{")), // 1
-            NodeSpec::Token(TokenSpec::separator(",")), // 2
-            NodeSpec::Token(TokenSpec::delimiter("}")), // 3
-            NodeSpec::Token(TokenSpec::separator(":")), // 4
-            NodeSpec::Token(TokenSpec::delimiter("[")), // 5
-            NodeSpec::Token(TokenSpec::delimiter("]")), // 6
-            NodeSpec::Token(TokenSpec::Regex { // 7
-                name: "string",
-                regex: Regex::new(".*").unwrap(),
-                precedence: 0,
-                can_empty: true,
-                can_space: true,
-                can_newline: true,
-                can_wrap: true,
-                semantics: RegexTokenSemantics::Literal
-            }),
-            NodeSpec::Token(TokenSpec::Regex { // 8
-                name: "number",
-                regex: Regex::new(r#"-?(?:0|[1-9]\d*)(?:\.\d+)?(?:[eE][+-]?\d+)?"#).unwrap(), // TODO
-                precedence: 10,
-                can_empty: false,
-                can_space: false,
-                can_newline: false,
-                can_wrap: false,
-                semantics: RegexTokenSemantics::Literal
-            }),
-            NodeSpec::Token(TokenSpec::keyword("true")), // 9 (11
-            NodeSpec::Token(TokenSpec::keyword("false")), // 10 (12
-            NodeSpec::Token(TokenSpec::keyword("null")), // 11 (13
-            // error node, precedence higher than string, error is handled by a catch all node like literal string
-            // unreachable from syntax rules
-            // **higher precedence** than string node (precedence only compared within candidate tokens)
-            NodeSpec::Token(TokenSpec::Regex { // 12 (14
-                name: "",
-                regex: Regex::new(".*").unwrap(),
-                precedence: 1,
-                can_empty: true,
-                can_space: true,
-                can_newline: true,
-                can_wrap: true,
-                semantics: RegexTokenSemantics::LexingError
-            }),
-            NodeSpec::Compose, // 13
-            unused_node_spec(), // 14
-            NodeSpec::Tree {  // 15 object
-                start: vec![1],
-                sep: vec![2],
-                end: vec![3]
-            },
-            NodeSpec::Compose,
-            NodeSpec::Tree {
-                start: vec![5],
-                sep: vec![2],
-                end: vec![6]
-            }
+            SearchScope::new("keys", TokenPattern::child_of(vec![7], 16, 0)),
+            SearchScope::new("strings", TokenPattern::kinds(vec![7])),
+            SearchScope::new("numbers", TokenPattern::kinds(vec![8])),
+        ],
+        vec![
+            // keys are strings too, but they are shown differently
+            (TokenPattern::child_of(vec![7], 16, 0), "string.key"),
         ],
         language()
     )
 }
 
+/// indexed by tree-sitter node kind id
+pub(crate) fn nodes() -> Vec<NodeSpec> {
+    vec![
+        unused_node_spec(), // 0
+        NodeSpec::Token(TokenSpec::delimiter("{")), // 1
+        NodeSpec::Token(TokenSpec::separator(",")), // 2
+        NodeSpec::Token(TokenSpec::delimiter("}")), // 3
+        NodeSpec::Token(TokenSpec::separator(":")), // 4
+        NodeSpec::Token(TokenSpec::delimiter("[")), // 5
+        NodeSpec::Token(TokenSpec::delimiter("]")), // 6
+        NodeSpec::Token(TokenSpec::Regex { // 7
+            name: "string",
+            regex: Regex::new(".*").unwrap(),
+            precedence: 0,
+            can_empty: true,
+            can_space: true,
+            can_newline: true,
+            can_wrap: true,
+            semantics: RegexTokenSemantics::Literal,
+            category: category::STRING,
+            shaping: Shaping { bidi: true, show_codepoints: true }
+        }),
+        NodeSpec::Token(TokenSpec::Regex { // 8
+            name: "number",
+            regex: Regex::new(r#"-?(?:0|[1-9]\d*)(?:\.\d+)?(?:[eE][+-]?\d+)?"#).unwrap(), // TODO
+            precedence: 10,
+            can_empty: false,
+            can_space: false,
+            can_newline: false,
+            can_wrap: false,
+            semantics: RegexTokenSemantics::Literal,
+            category: category::NUMBER,
+            shaping: Shaping::default()
+        }),
+        NodeSpec::Token(TokenSpec::keyword("true").with_category(category::CONSTANT)), // 9 (11
+        NodeSpec::Token(TokenSpec::keyword("false").with_category(category::CONSTANT)), // 10 (12
+        NodeSpec::Token(TokenSpec::keyword("null").with_category(category::CONSTANT)), // 11 (13
+        // error node, precedence higher than string, error is handled by a catch all node like literal string
+        // unreachable from syntax rules
+        // **higher precedence** than string node (precedence only compared within candidate tokens)
+        NodeSpec::Token(TokenSpec::Regex { // 12 (14
+            name: "",
+            regex: Regex::new(".*").unwrap(),
+            precedence: 1,
+            can_empty: true,
+            can_space: true,
+            can_newline: true,
+            can_wrap: true,
+            semantics: RegexTokenSemantics::LexingError,
+            category: category::INVALID,
+            shaping: Shaping { bidi: false, show_codepoints: true }
+        }),
+        NodeSpec::Compose, // 13
+        unused_node_spec(), // 14
+        NodeSpec::Tree {  // 15 object
+            start: vec![1],
+            sep: vec![2],
+            end: vec![3]
+        },
+        NodeSpec::Compose,
+        NodeSpec::Tree {
+            start: vec![5],
+            sep: vec![2],
+            end: vec![6]
+        }
+    ]
+}
+
+pub(crate) fn rules() -> Vec<Rule> {
+    vec![
+        Rule::new("document", Syntax::Ref("value")),
+        Rule::new("value", Syntax::Choice(vec![
+            Syntax::Ref("object"),
+            Syntax::Ref("array"),
+            Syntax::regex("number"),
+            Syntax::regex("string"),
+            Syntax::constant("true"),
+            Syntax::constant("false"),
+            Syntax::constant("null"),
+        ])),
+        Rule::new("object", Syntax::tree(
+            Syntax::constant("{"),
+            Syntax::Ref("pair"),
+            Syntax::constant(","),
+            Syntax::constant("}")
+        )),
+        Rule::new("pair", Syntax::Combine(vec![
+            Syntax::regex("string"),
+            Syntax::constant(":"),
+            Syntax::Ref("value"),
+        ])),
+        Rule::new("array", Syntax::tree(
+            Syntax::constant("["),
+            Syntax::Ref("value"),
+            Syntax::constant(","),
+            Syntax::constant("]")
+        )),
+    ]
+}
+
 extern "C" { fn tree_sitter_json() -> tree_sitter::Language; }
 
 
-fn language() -> tree_sitter::Language {
+pub(crate) fn language() -> tree_sitter::Language {
     unsafe { tree_sitter_json() }
 }
@@ -0,0 +1,8 @@
+use editor::*;
+
+pub mod languages;
+pub mod editor;
+pub mod data; pub use data::*;
+pub mod spec; pub use spec::*;
+pub mod language; pub use language::*;
+pub mod grammar; pub use grammar::*;
@@ -1,4 +1,5 @@
 use regex::Regex;
+use tree_sitter::Node;
 
 
 #[derive(Clone, Debug)]
@@ -17,6 +18,22 @@ pub enum RegexTokenSemantics {
     LexingError
 }
 
+/// dotted semantic categories, a theme colors a category by the longest prefix it knows, so `string.key` falls
+/// back to `string`. languages can use their own
+pub mod category {
+    pub const IDENTIFIER: &str = "identifier";
+    pub const TYPE: &str = "type";
+    pub const OPERATOR: &str = "operator";
+    pub const KEYWORD: &str = "keyword";
+    pub const CONSTANT: &str = "constant";
+    pub const NUMBER: &str = "number";
+    pub const STRING: &str = "string";
+    pub const PROPERTY: &str = "property";
+    pub const BRACKET: &str = "punctuation.bracket";
+    pub const SEPARATOR: &str = "punctuation.separator";
+    pub const INVALID: &str = "invalid";
+}
+
 #[derive(Clone, Debug)]
 pub enum TokenSpec {
     Constant {
@@ -24,8 +41,9 @@ pub enum TokenSpec {
         str: &'static str,
         /// don't need user to type space to decide new boundary
         eager: bool,
-        /// currently used by highlighter
-        semantics: ConstantTokenSemantics
+        semantics: ConstantTokenSemantics,
+        /// see `category`, can be overridden by the context, see `Language::category`
+        category: &'static str
     },
     Regex {
         name: &'static str,
@@ -40,10 +58,20 @@ pub enum TokenSpec {
         can_newline: bool,
         /// can wrap new line if too long
         can_wrap: bool,
-        /// currently used by highlighter
-        semantics: RegexTokenSemantics
+        semantics: RegexTokenSemantics,
+        category: &'static str,
+        shaping: Shaping
     }
-    // LATER can have shaping settings: logic order or not, complex shaping or not, show codepoint instead...
+}
+
+/// how the text of a token is shown, constant tokens always use the default
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Shaping {
+    /// bidi reordering, otherwise the text is shown left to right in logical order. complex shaping, like joining
+    /// and ligatures, is always done
+    pub bidi: bool,
+    /// invisible and control characters are shown escaped, like `\u{200F}`
+    pub show_codepoints: bool
 }
 
 impl TokenSpec {
@@ -57,6 +85,36 @@ impl TokenSpec {
         }
     }
 
+    /// how the token is called in user interface
+    pub fn name(&self) -> &'static str {
+        match self {
+            TokenSpec::Constant { str, .. } => *str,
+            TokenSpec::Regex { name, .. } => *name
+        }
+    }
+
+    pub fn category(&self) -> &'static str {
+        match self {
+            TokenSpec::Constant { category, .. } => *category,
+            TokenSpec::Regex { category, .. } => *category
+        }
+    }
+
+    pub fn shaping(&self) -> Shaping {
+        match self {
+            TokenSpec::Constant {..} => Shaping::default(),
+            TokenSpec::Regex { shaping, .. } => *shaping
+        }
+    }
+
+    pub fn with_category(mut self, c: &'static str) -> TokenSpec {
+        match &mut self {
+            TokenSpec::Constant { category, .. } => *category = c,
+            TokenSpec::Regex { category, .. } => *category = c
+        }
+        self
+    }
+
     pub fn can_empty(&self) -> bool {
         match self {
             TokenSpec::Constant {..} => false,
@@ -64,6 +122,20 @@ impl TokenSpec {
         }
     }
 
+    pub fn can_newline(&self) -> bool {
+        match self {
+            TokenSpec::Constant {..} => false,
+            TokenSpec::Regex { can_newline, .. } => *can_newline
+        }
+    }
+
+    pub fn can_wrap(&self) -> bool {
+        match self {
+            TokenSpec::Constant {..} => false,
+            TokenSpec::Regex { can_wrap, .. } => *can_wrap
+        }
+    }
+
     pub fn is_separator(&self) -> bool {
         match self {
             TokenSpec::Constant { semantics, .. } => match semantics {
@@ -74,6 +146,13 @@ impl TokenSpec {
         }
     }
 
+    pub fn is_delimiter(&self) -> bool {
+        match self {
+            TokenSpec::Constant { semantics: ConstantTokenSemantics::Delimiter, .. } => true,
+            _ => false
+        }
+    }
+
     pub fn accept(&self, string: &str) -> bool {
         match self {
             TokenSpec::Constant { str, .. } => *str == string,
@@ -85,7 +164,8 @@ impl TokenSpec {
         TokenSpec::Constant {
             str,
             eager: true,
-            semantics: ConstantTokenSemantics::Delimiter
+            semantics: ConstantTokenSemantics::Delimiter,
+            category: category::BRACKET
         }
     }
 
@@ -93,7 +173,8 @@ impl TokenSpec {
         TokenSpec::Constant {
             str,
             eager: true,
-            semantics: ConstantTokenSemantics::Separator
+            semantics: ConstantTokenSemantics::Separator,
+            category: category::SEPARATOR
         }
     }
 
@@ -101,7 +182,8 @@ impl TokenSpec {
         TokenSpec::Constant {
             str,
             eager: false,
-            semantics: ConstantTokenSemantics::Keyword
+            semantics: ConstantTokenSemantics::Keyword,
+            category: category::KEYWORD
         }
     }
 }
@@ -109,11 +191,21 @@ impl TokenSpec {
 #[derive(Clone, Debug)]
 pub enum NodeSpec {
     Token(TokenSpec),
-    Tree { // TODO this doesn't handle like Scheme where the break point is positional
+    Tree {
         start: Vec<u16>,
         sep: Vec<u16>,
         end: Vec<u16>
     },
+    /// the break point is positional, like Scheme `(define name` + indented body, or ML `if cond` + aligned branches:
+    /// the first `head` children stay on the first line, the rest each starts a new line
+    Positional {
+        /// the first child, like `(`, empty when the node has no delimiters
+        start: Vec<u16>,
+        head: usize,
+        /// the rest are aligned with the last head child, instead of indented
+        align: bool,
+        end: Vec<u16>
+    },
     Compose,
     Error
 }
@@ -126,6 +218,84 @@ impl NodeSpec {
             _ => panic!()
         }
     }
+
+    /// the start and end delimiters of a tree node, or a positional one with delimiters. these nodes get nesting
+    /// depth, folds, guides and auto closing
+    pub fn delimiters(&self) -> Option<(&[u16], &[u16])> {
+        match self {
+            NodeSpec::Tree { start, end, .. } => Some((start, end)),
+            NodeSpec::Positional { start, end, .. } if !start.is_empty() => Some((start, end)),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Margin {
+    pub left: f64,
+    pub right: f64
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BreakHint {
+    /// break when the node doesn't fit in the line
+    Auto,
+    Always,
+    /// stays in one line unless a child is already multiple lines
+    Never
+}
+
+/// where the end delimiter goes when a tree is broken into multiple lines
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EndDelimiter {
+    /// on its own line, aligned with the start of the tree
+    Align,
+    /// at the end of the last child, like Lisp does
+    Hang
+}
+
+#[derive(Clone, Debug)]
+pub struct LayoutStyle {
+    pub indent: f64,
+    pub separator: Margin,
+    pub delimiter: Margin,
+    pub keyword: Margin,
+    /// used by all regex tokens
+    pub regex: Margin,
+    pub end_delimiter: EndDelimiter,
+    /// overrides `BreakHint::Auto` for some node types
+    pub breaks: Vec<(u16, BreakHint)>
+}
+
+impl LayoutStyle {
+    pub fn margin(&self, spec: &TokenSpec) -> &Margin {
+        match spec {
+            TokenSpec::Constant { semantics, .. } => match semantics {
+                ConstantTokenSemantics::Separator => &self.separator,
+                ConstantTokenSemantics::Delimiter => &self.delimiter,
+                ConstantTokenSemantics::Keyword => &self.keyword,
+            },
+            TokenSpec::Regex { .. } => &self.regex
+        }
+    }
+
+    pub fn break_hint(&self, tp: u16) -> BreakHint {
+        self.breaks.iter().find(|n| n.0 == tp).map_or(BreakHint::Auto, |n| n.1)
+    }
+}
+
+impl Default for LayoutStyle {
+    fn default() -> Self {
+        LayoutStyle {
+            indent: 12.0,
+            separator: Margin { left: 2.0, right: 2.0 },
+            delimiter: Margin { left: 8.0, right: 8.0 },
+            keyword: Margin { left: 8.0, right: 8.0 },
+            regex: Margin { left: 8.0, right: 8.0 },
+            end_delimiter: EndDelimiter::Align,
+            breaks: vec![]
+        }
+    }
 }
 
 pub fn unused_node_spec() -> NodeSpec {
@@ -138,6 +308,8 @@ pub fn unused_node_spec() -> NodeSpec {
         can_newline: false,
         can_wrap: false,
         semantics: RegexTokenSemantics::Unspecified,
+        category: "",
+        shaping: Shaping::default()
     })
 }
 
@@ -183,8 +355,80 @@ pub enum Syntax {
     },
 }
 
+impl Syntax {
+    pub fn constant(str: &'static str) -> Syntax {
+        Syntax::Token(TokenRef::Constant(str))
+    }
+
+    pub fn regex(name: &'static str) -> Syntax {
+        Syntax::Token(TokenRef::Regex(name))
+    }
+
+    pub fn tree(start: Syntax, child: Syntax, sep: Syntax, end: Syntax) -> Syntax {
+        Syntax::Tree {
+            start: Box::new(start),
+            child: Box::new(child),
+            sep: Box::new(sep),
+            end: Box::new(end)
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Rule {
-    name: &'static str,
-    body: Syntax
+    pub name: &'static str,
+    pub body: Syntax
+}
+
+impl Rule {
+    pub fn new(name: &'static str, body: Syntax) -> Rule {
+        Rule { name, body }
+    }
+}
+
+/// matches tokens by type, and optionally by where they are in the tree
+#[derive(Clone, Debug)]
+pub struct TokenPattern {
+    /// empty matches any token
+    pub kinds: Vec<u16>,
+    /// node type of the parent and the child position inside it, like the key of a json pair
+    pub parent: Option<(u16, usize)>
+}
+
+impl TokenPattern {
+    pub fn any() -> TokenPattern {
+        TokenPattern { kinds: vec![], parent: None }
+    }
+
+    pub fn kinds(kinds: Vec<u16>) -> TokenPattern {
+        TokenPattern { kinds, parent: None }
+    }
+
+    pub fn child_of(kinds: Vec<u16>, parent: u16, position: usize) -> TokenPattern {
+        TokenPattern { kinds, parent: Some((parent, position)) }
+    }
+
+    /// the tree node of the token is only looked up when needed, the siblings are never enumerated
+    pub fn matches<'a>(&self, tp: u16, node: impl FnOnce() -> Option<Node<'a>>) -> bool {
+        (self.kinds.is_empty() || self.kinds.contains(&tp)) && match self.parent {
+            None => true,
+            Some((kind, position)) => node().map_or(false, |n| match n.parent() {
+                Some(p) => p.kind_id() == kind && p.child(position) == Some(n),
+                None => false
+            })
+        }
+    }
+}
+
+/// a named token filter for find and replace
+#[derive(Clone, Debug)]
+pub struct SearchScope {
+    pub name: &'static str,
+    pub pattern: TokenPattern
+}
+
+impl SearchScope {
+    pub fn new(name: &'static str, pattern: TokenPattern) -> SearchScope {
+        SearchScope { name, pattern }
+    }
 }